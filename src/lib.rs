@@ -1,14 +1,20 @@
 use chrono::{DateTime, Local};
-use clap::{arg, Parser};
+use clap::Parser;
 use colored::*;
+use feruca::Collator;
 use regex::Regex;
-use serde::Serialize;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
-use std::io;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use std::{fmt, fs};
 
 #[derive(Parser, Debug)]
@@ -29,16 +35,67 @@ use std::{fmt, fs};
      "
 )]
 pub struct Args {
-    #[arg(default_value = ".", help = "Root directory to start traversal")]
-    pub path: PathBuf,
+    // first, so it reads argument positions before the fields below take their values
+    #[command(flatten)]
+    pub positions: FlagPositions,
+
+    #[arg(
+        value_name = "PATH",
+        help = "Root directory to start traversal [default: $MYTREE_ROOT, or . when unset]"
+    )]
+    pub path: Option<PathBuf>,
+
+    #[arg(
+        long = "glob-root",
+        default_value_t = false,
+        help = "Treat PATH as a glob, e.g. 'src/**/*.rs', and show every match under its literal leading directories"
+    )]
+    pub glob_root: bool,
 
     #[arg(
         short = 's',
         long = "sort",
-        help = "Supply the argument with 'fs' to sort by file size, 'ts' to sort by last updated timestamp, or nothing to sort alphabetically (default)"
+        help = "Supply the argument with 'fs' to sort by file size, 'ts' to sort by last updated timestamp, 'children' to put directories with the most direct entries first, or nothing to sort alphabetically (default)"
     )]
     pub sort_by: Option<String>,
 
+    #[arg(
+        long = "reverse",
+        default_value_t = false,
+        help = "Reverse the sort order"
+    )]
+    pub reverse: bool,
+
+    #[arg(
+        long = "shuffle",
+        default_value_t = false,
+        conflicts_with = "sort_by",
+        help = "List each directory in random order instead of sorting (see --seed)"
+    )]
+    pub shuffle: bool,
+
+    #[arg(
+        long = "seed",
+        value_name = "N",
+        requires = "shuffle",
+        help = "Seed for --shuffle so the same order can be reproduced"
+    )]
+    pub seed: Option<u64>,
+
+    #[arg(
+        long = "pin",
+        value_name = "NAME",
+        help = "List entries named NAME first in their directory, in the order given (repeatable)"
+    )]
+    pub pin: Vec<String>,
+
+    #[arg(
+        long = "locale-sort",
+        default_value_t = false,
+        help = "Sort accented names alongside their base letters (e.g. 'é' with 'e') when sorting alphabetically"
+    )]
+    pub locale_sort: bool,
+
     #[arg(
         short = 'e',
         long = "extension",
@@ -48,6 +105,13 @@ pub struct Args {
     )]
     pub extension_filters: Option<Vec<String>>,
 
+    #[arg(
+        long = "case-sensitive-ext",
+        default_value_t = false,
+        help = "Match --extension filters exactly instead of ignoring case (e.g. .C vs .c)"
+    )]
+    pub case_sensitive_ext: bool,
+
     #[arg(
         short = 'a',
         long = "all",
@@ -56,631 +120,8171 @@ pub struct Args {
     )]
     pub show_hidden: bool,
 
+    #[arg(
+        long = "no-hidden-dirs",
+        default_value_t = false,
+        help = "Skip hidden directories such as .git even when hidden files are shown"
+    )]
+    pub no_hidden_dirs: bool,
+
     #[arg(
         short = 'r',
         long = "regex",
-        help = "Filter entries by matching name with regex"
+        help = "Filter entries by matching name with regex (repeatable); use --glob for shell patterns like *.rs"
     )]
-    pub regex: Option<String>,
+    pub regex: Vec<String>,
 
     #[arg(
-        short = 'l',
-        long = "long",
-        default_value_t = false,
-        help = "Enable long format output with file size and timestamps"
+        long = "highlight",
+        value_name = "REGEX",
+        help = "Show every entry but render names matching REGEX in bold reverse video"
     )]
-    pub long_format: bool,
+    pub highlight: Option<String>,
 
     #[arg(
-        short = 'j',
-        long = "json",
-        value_name = "FILE",
-        num_args = 0..=1,
-        default_missing_value = "tree.json",
-        help = "Write directory tree as JSON (optionally specify FILE)"
+        long = "regex-mode",
+        help = "Supply the argument with 'all' to require every regex to match, or 'any' to require at least one (default)"
     )]
-    pub write_json: Option<String>,
-}
+    pub regex_mode: Option<String>,
 
-struct PrintOptions {
-    sort_by: SortBy,
-    extension_filters: Option<HashSet<String>>,
-    show_hidden: bool,
-    regex_filter: Option<Regex>,
-    long_format: bool,
-    write_json: Option<String>,
-}
+    #[arg(
+        long = "context",
+        value_name = "N",
+        default_value_t = 0,
+        help = "Also show up to N dimmed siblings around each file kept by --extension or --regex"
+    )]
+    pub context: usize,
 
-struct Stats {
-    dirs: usize,
-    files: usize,
-    size: u64,
-}
+    #[arg(
+        long = "include",
+        visible_alias = "glob",
+        value_name = "GLOB",
+        help = "Only show files whose name or path matches the glob (repeatable)"
+    )]
+    pub include: Vec<String>,
 
-struct EntryMeta {
-    name: String,
-    path: PathBuf,
-    size: u64,
-    mtime: SystemTime,
-    is_dir: bool,
-}
+    #[arg(
+        long = "exclude",
+        value_name = "GLOB",
+        help = "Skip files and directories whose name or path matches the glob (repeatable)"
+    )]
+    pub exclude: Vec<String>,
 
-#[derive(Debug, Clone)]
-enum SortBy {
-    Alphabetical,
-    FileSize,
-    LastUpdatedTimestamp,
-}
+    #[arg(
+        long = "exclude-from",
+        value_name = "FILE",
+        help = "Read exclude globs from FILE, one per line ('-' reads from stdin)"
+    )]
+    pub exclude_from: Option<PathBuf>,
 
-#[derive(Debug)]
-pub struct ArgParseError {
-    pub details: ArgParseErrorType,
-}
+    #[arg(
+        long = "exclude-vcs",
+        default_value_t = false,
+        help = "Skip version-control metadata (.git, .hg, .svn, .bzr), e.g. when combined with --all"
+    )]
+    pub exclude_vcs: bool,
 
-#[derive(Debug)]
-pub enum ArgParseErrorType {
-    SortFlag(String),
-    BadExtension(String),
-    BadRegex(String),
-}
+    #[arg(
+        long = "prune-dir",
+        value_name = "GLOB",
+        help = "Skip directories whose name or path matches the glob, without descending (files are unaffected; repeatable)"
+    )]
+    pub prune_dir: Vec<String>,
 
-impl fmt::Display for ArgParseErrorType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ArgParseErrorType::SortFlag(flag) => write!(
-                f,
-                "invalid sort flag \"{flag}\" (expected \"fs\" or \"ts\")"
-            ),
-            ArgParseErrorType::BadExtension(ext) => write!(f, "invalid extension \"{ext}\""),
-            ArgParseErrorType::BadRegex(msg) => write!(f, "invalid regex -> {msg}"),
-        }
-    }
-}
+    #[arg(
+        long = "exclude-larger-than",
+        value_name = "SIZE",
+        help = "Skip files larger than SIZE (e.g. 500K, 10M, 1.5G) in both the tree and the totals"
+    )]
+    pub exclude_larger_than: Option<String>,
 
-impl fmt::Display for ArgParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "argument error -> {}", self.details)
-    }
-}
+    #[arg(
+        long = "size",
+        value_name = "EXPR",
+        allow_hyphen_values = true,
+        help = "Only show files whose size matches EXPR: +10M (more than), -1K (less than), 4K (exactly) or 100..500 (inclusive; either end may be left open)"
+    )]
+    pub size: Option<String>,
 
-impl Error for ArgParseError {}
+    #[arg(
+        long = "exclude-empty-files",
+        visible_alias = "no-empty",
+        default_value_t = false,
+        conflicts_with = "no_metadata",
+        help = "Skip files of zero bytes; directories are kept"
+    )]
+    pub exclude_empty_files: bool,
 
-#[derive(Debug)]
-pub struct TreeParseError {
-    pub details: TreeParseType,
-}
+    #[arg(
+        long = "min-lines",
+        value_name = "N",
+        help = "Only show text files with at least N lines (binary files are left out)"
+    )]
+    pub min_lines: Option<usize>,
 
-#[derive(Debug)]
-pub enum TreeParseType {
-    Io(String),
-    InvalidInput(String),
-}
+    #[arg(
+        long = "changed",
+        default_value_t = false,
+        help = "Only show files with staged or unstaged changes against HEAD (requires git)"
+    )]
+    pub changed: bool,
 
-impl fmt::Display for TreeParseType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            TreeParseType::Io(msg) => write!(f, "IO error -> {msg}"),
-            TreeParseType::InvalidInput(msg) => write!(f, "{msg}"),
-        }
-    }
-}
+    #[arg(
+        long = "repo-root",
+        default_value_t = false,
+        help = "Scan from the top of the git repository containing PATH instead of PATH itself"
+    )]
+    pub repo_root: bool,
 
-impl fmt::Display for TreeParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.details)
-    }
-}
+    #[arg(
+        long = "since",
+        value_name = "REF",
+        conflicts_with = "changed",
+        help = "Only show files that differ between git REF (tag, branch or commit) and the working tree"
+    )]
+    pub since: Option<String>,
 
-impl Error for TreeParseError {}
+    #[arg(
+        long = "newest",
+        value_name = "N",
+        help = "Only show the N most recently modified files in the whole tree"
+    )]
+    pub newest: Option<usize>,
 
-impl From<io::Error> for TreeParseError {
-    fn from(e: io::Error) -> Self {
-        TreeParseError {
-            details: TreeParseType::Io(e.to_string()),
-        }
-    }
-}
+    #[arg(
+        long = "oldest",
+        value_name = "N",
+        conflicts_with = "newest",
+        help = "Only show the N least recently modified files in the whole tree"
+    )]
+    pub oldest: Option<usize>,
 
-#[derive(Debug)]
-pub enum ParseError {
-    Args(ArgParseError),
-    Tree(TreeParseError),
-}
+    #[arg(
+        long = "min-depth",
+        value_name = "N",
+        help = "Only show entries at depth N or deeper (shallower directories are still traversed)"
+    )]
+    pub min_depth: Option<usize>,
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ParseError::Args(e) => Debug::fmt(&e, f),
-            ParseError::Tree(e) => Debug::fmt(&e, f),
-        }
-    }
-}
+    #[arg(
+        long = "max-depth",
+        value_name = "N",
+        help = "Do not descend deeper than N levels below the root"
+    )]
+    pub max_depth: Option<usize>,
 
-impl Error for ParseError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match self {
-            ParseError::Args(e) => Some(e),
-            ParseError::Tree(e) => Some(e),
-        }
-    }
-}
+    #[arg(
+        short = '1',
+        long = "no-recursion",
+        default_value_t = false,
+        conflicts_with = "max_depth",
+        help = "List only the root's immediate children, like ls (same as --max-depth 1)"
+    )]
+    pub no_recursion: bool,
 
-impl From<ArgParseError> for ParseError {
-    fn from(e: ArgParseError) -> Self {
-        Self::Args(e)
-    }
-}
-impl From<TreeParseError> for ParseError {
-    fn from(e: TreeParseError) -> Self {
-        Self::Tree(e)
-    }
-}
+    #[arg(
+        long = "collapse",
+        default_value_t = false,
+        help = "Merge chains of directories that each contain a single subdirectory into one line"
+    )]
+    pub collapse: bool,
 
-impl From<ParseError> for io::Error {
-    fn from(e: ParseError) -> io::Error {
-        io::Error::other(e)
-    }
-}
+    #[arg(
+        short = 'x',
+        long = "one-file-system",
+        default_value_t = false,
+        help = "Skip entries on a different filesystem from the root"
+    )]
+    pub one_file_system: bool,
 
-#[derive(Debug, Serialize)]
-struct TreeNode {
-    name: String,
-    path: PathBuf,
-    size: u64,
-    mtime: SystemTime,
-    is_dir: bool,
-    children: Option<Vec<TreeNode>>,
-}
+    #[arg(
+        long = "stay-on",
+        value_name = "PATH",
+        conflicts_with = "one_file_system",
+        help = "Skip entries on a different filesystem from PATH"
+    )]
+    pub stay_on: Option<PathBuf>,
 
-fn create_print_options_from_args(args: Args) -> Result<PrintOptions, ParseError> {
-    let sort_by = match args.sort_by.as_deref() {
-        Some("fs") => SortBy::FileSize,
-        Some("ts") => SortBy::LastUpdatedTimestamp,
-        Some(bad) => {
-            return Err(ParseError::Args(ArgParseError {
-                details: ArgParseErrorType::SortFlag(bad.into()),
-            }));
-        }
-        None => SortBy::Alphabetical,
-    };
+    #[arg(
+        long = "follow-symlinks",
+        default_value_t = false,
+        help = "Descend into symlinked directories, except links to a directory they are inside (cycles)"
+    )]
+    pub follow_symlinks: bool,
 
-    let extension_filters = if let Some(list) = args.extension_filters {
-        let mut set = HashSet::with_capacity(list.len());
-        for raw in list {
-            let ext = raw.trim_start_matches('.');
-            if ext.is_empty() {
-                return Err(ParseError::Args(ArgParseError {
-                    details: ArgParseErrorType::BadExtension(raw),
-                }));
-            }
-            set.insert(ext.to_ascii_lowercase());
-        }
-        Some(set)
-    } else {
-        None
-    };
+    // the walk always resolves the root itself; this only rules out --follow-symlinks
+    #[arg(
+        long = "follow-root-only",
+        default_value_t = false,
+        conflicts_with = "follow_symlinks",
+        help = "Follow PATH if it is a symlink but list symlinks inside it as leaves (the default, made explicit)"
+    )]
+    pub follow_root_only: bool,
 
-    let regex_filter = if let Some(pattern) = args.regex {
-        match Regex::new(&pattern) {
-            Ok(re) => Some(re),
-            Err(e) => {
-                return Err(ParseError::Args(ArgParseError {
-                    details: ArgParseErrorType::BadRegex(format!(
-                        "invalid regex \"{pattern}\": {e}"
-                    )),
-                }));
-            }
-        }
-    } else {
-        None
-    };
+    #[arg(
+        short = 'd',
+        long = "dirs-only",
+        default_value_t = false,
+        help = "List directories only"
+    )]
+    pub dirs_only: bool,
 
-    Ok(PrintOptions {
-        sort_by,
-        extension_filters,
-        show_hidden: args.show_hidden,
-        regex_filter,
-        long_format: args.long_format,
-        write_json: args.write_json,
+    #[arg(
+        long = "only",
+        value_name = "KIND",
+        conflicts_with = "dirs_only",
+        help = "Only list one kind of entry: 'file', 'dir' (same as --dirs-only), 'symlink' or 'empty' (zero-byte files)"
+    )]
+    pub only: Option<String>,
+
+    #[arg(
+        long = "sample",
+        value_name = "N",
+        help = "Show only the first N entries of each directory, without noting what was left out"
+    )]
+    pub sample: Option<usize>,
+
+    #[arg(
+        long = "count-badge",
+        default_value_t = false,
+        help = "Append the number of files beneath each directory, e.g. (3 files)"
+    )]
+    pub count_badge: bool,
+
+    #[arg(
+        long = "child-count",
+        default_value_t = false,
+        help = "Append the number of direct children to each directory, e.g. [12]"
+    )]
+    pub child_count: bool,
+
+    #[arg(
+        long = "fold-identical",
+        default_value_t = false,
+        help = "Collapse directories whose contents repeat an earlier directory into <same as PATH>"
+    )]
+    pub fold_identical: bool,
+
+    #[arg(
+        long = "size-inline",
+        default_value_t = false,
+        help = "Append the size after each name, e.g. main.rs (1.2 KB); directories show the total of their files"
+    )]
+    pub size_inline: bool,
+
+    #[arg(
+        long = "running-total",
+        default_value_t = false,
+        help = "Append the cumulative size of all files printed so far to each file"
+    )]
+    pub running_total: bool,
+
+    #[arg(
+        long = "mark-empty",
+        default_value_t = false,
+        help = "Show directories that contain nothing at all, marked (empty)"
+    )]
+    pub mark_empty: bool,
+
+    #[arg(
+        long = "overview",
+        default_value_t = false,
+        help = "Print one line per directory with its file count and subtree size instead of the tree"
+    )]
+    pub overview: bool,
+
+    #[arg(
+        long = "indent",
+        value_name = "N",
+        help = "Width of each tree indentation level, from 2 to 8 columns [default: 4]"
+    )]
+    pub indent: Option<usize>,
+
+    #[arg(
+        long = "compact",
+        default_value_t = false,
+        help = "Narrow layout: 2-column indentation and tight connectors, without -l, --count-badge, --child-count, --size-inline or --running-total; any of those (or --indent) given after --compact still applies"
+    )]
+    pub compact: bool,
+
+    #[arg(
+        long = "outline",
+        default_value_t = false,
+        help = "Indent entries with spaces only, no connector glyphs, and mark directories with a trailing /"
+    )]
+    pub outline: bool,
+
+    #[arg(
+        long = "bottom-up",
+        default_value_t = false,
+        help = "Print the tree upside down, with each directory below its contents"
+    )]
+    pub bottom_up: bool,
+
+    #[arg(
+        long = "max-name-length",
+        value_name = "N",
+        help = "Shorten displayed names longer than N characters, ending them with '…'"
+    )]
+    pub max_name_length: Option<usize>,
+
+    #[arg(
+        long = "dir-prefix",
+        value_name = "TEXT",
+        help = "Text printed between the connector and each directory name (e.g. '▸ ')"
+    )]
+    pub dir_prefix: Option<String>,
+
+    #[arg(
+        long = "file-prefix",
+        value_name = "TEXT",
+        help = "Text printed between the connector and each non-directory name"
+    )]
+    pub file_prefix: Option<String>,
+
+    #[arg(
+        long = "heat",
+        default_value_t = false,
+        help = "Color entries from green to red by how long ago they were modified"
+    )]
+    pub heat: bool,
+
+    #[arg(
+        long = "heat-buckets",
+        value_name = "DURATIONS",
+        value_delimiter = ',',
+        requires = "heat",
+        help = "Age thresholds for --heat, comma separated [default: 1d,7d,30d,365d]"
+    )]
+    pub heat_buckets: Option<Vec<String>>,
+
+    #[arg(
+        long = "size-heat",
+        default_value_t = false,
+        conflicts_with = "heat",
+        help = "Style entries by size, from dimmed for tiny files to bold red past 1 GB; directories use their subtree size"
+    )]
+    pub size_heat: bool,
+
+    #[arg(
+        long = "depth-colors",
+        default_value_t = false,
+        conflicts_with_all = ["heat", "size_heat"],
+        help = "Color entries by nesting level, cycling through a fixed palette"
+    )]
+    pub depth_colors: bool,
+
+    #[arg(
+        short = 'l',
+        long = "long",
+        default_value_t = false,
+        help = "Enable long format output with file size and timestamps"
+    )]
+    pub long_format: bool,
+
+    #[arg(
+        long = "inode",
+        default_value_t = false,
+        requires = "long_format",
+        help = "Show inode numbers in long format output"
+    )]
+    pub show_inode: bool,
+
+    #[arg(
+        short = 'p',
+        long = "perms",
+        default_value_t = false,
+        help = "Show permissions before each name, e.g. [drwxr-xr-x]"
+    )]
+    pub show_perms: bool,
+
+    #[arg(
+        long = "align",
+        default_value_t = false,
+        requires = "long_format",
+        help = "Print long format as one row per entry with a right-justified size column"
+    )]
+    pub align: bool,
+
+    #[arg(
+        long = "dedup-links",
+        default_value_t = false,
+        help = "Count the size of hard-linked files only once and mark repeated links"
+    )]
+    pub dedup_links: bool,
+
+    #[arg(
+        long = "no-metadata",
+        default_value_t = false,
+        conflicts_with_all = [
+            "sort_by", "exclude_larger_than", "size", "newest", "oldest",
+            "one_file_system", "stay_on", "dedup_links", "heat", "size_heat",
+            "size_inline", "running_total", "long_format", "show_inode", "compare",
+            "show_perms", "depth_weighted",
+        ],
+        help = "Skip reading size and time for each entry to list structure faster; exports show sizes of 0"
+    )]
+    pub no_metadata: bool,
+
+    #[arg(
+        short = 'j',
+        long = "json",
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "tree.json",
+        help = "Write directory tree as JSON (optionally specify FILE)"
+    )]
+    pub write_json: Option<String>,
+
+    #[arg(
+        long = "csv",
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "tree.csv",
+        help = "Write directory tree as CSV (optionally specify FILE)"
+    )]
+    pub write_csv: Option<String>,
+
+    #[arg(
+        long = "manifest",
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "tree.sha256",
+        help = "Write a SHA-256 manifest of every file, checkable with 'sha256sum -c' from the root (optionally specify FILE)"
+    )]
+    pub manifest: Option<String>,
+
+    #[arg(
+        long = "compare",
+        value_name = "FILE",
+        help = "Compare the tree against a snapshot written earlier with --json, list added, removed and changed entries, and fail if there are any"
+    )]
+    pub compare: Option<PathBuf>,
+
+    #[arg(
+        long = "svg",
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "tree.svg",
+        help = "Render the tree to SVG with Graphviz 'dot', or write the .dot file if dot is not installed (optionally specify FILE)"
+    )]
+    pub svg: Option<String>,
+
+    #[arg(
+        long = "sexp",
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "tree.sexp",
+        help = "Write directory tree as S-expressions, e.g. (dir \"src\" (file \"main.rs\" 1234)) (optionally specify FILE)"
+    )]
+    pub sexp: Option<String>,
+
+    #[arg(
+        long = "web",
+        value_name = "DIR",
+        help = "Write a static viewer to DIR: tree.json (as --json) and an index.html that shows it as a collapsible tree"
+    )]
+    pub web: Option<String>,
+
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long = "sqlite",
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "tree.db",
+        help = "Write directory tree to an SQLite database table named entries (optionally specify FILE); fails if FILE already has one"
+    )]
+    pub write_sqlite: Option<String>,
+
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        default_value_t = false,
+        help = "Do not print the tree to the terminal (exports are still written)"
+    )]
+    pub quiet: bool,
+
+    #[arg(
+        long = "summary-json",
+        default_value_t = false,
+        help = "Print the directory, file and byte totals as a JSON object after the tree (alone with -q)"
+    )]
+    pub summary_json: bool,
+
+    #[arg(
+        long = "summary-for",
+        value_name = "PATH",
+        help = "Also print the directory, file and byte totals for the subtree at PATH (relative to the root or the current directory)"
+    )]
+    pub summary_for: Option<PathBuf>,
+
+    #[arg(
+        long = "disk",
+        default_value_t = false,
+        help = "Also print the total, used and free space of the filesystem holding the root (Unix only)"
+    )]
+    pub disk: bool,
+
+    #[arg(
+        long = "count",
+        value_name = "EXT",
+        help = "Print only the number of files with this extension, one line per --count (no tree)"
+    )]
+    pub count: Vec<String>,
+
+    #[arg(
+        long = "paths-only",
+        default_value_t = false,
+        help = "Print only file paths, one per line, sorted and deduplicated (relative or absolute like PATH); no tree"
+    )]
+    pub paths_only: bool,
+
+    #[arg(
+        long = "index",
+        default_value_t = false,
+        conflicts_with = "paths_only",
+        help = "Print only directory paths relative to the root, one per line and sorted; no tree"
+    )]
+    pub index: bool,
+
+    #[arg(
+        long = "broken-links",
+        default_value_t = false,
+        conflicts_with_all = ["paths_only", "index"],
+        help = "Print only symlinks whose target does not exist, one per line as LINK -> TARGET; no tree"
+    )]
+    pub broken_links: bool,
+
+    #[arg(
+        long = "category-summary",
+        default_value_t = false,
+        help = "Break the file count down by category (code, docs, images, ...) after the summary"
+    )]
+    pub category_summary: bool,
+
+    #[arg(
+        long = "breakdown",
+        default_value_t = false,
+        help = "Split the summary into entries directly under the root and nested ones"
+    )]
+    pub breakdown: bool,
+
+    #[arg(
+        long = "legend",
+        default_value_t = false,
+        help = "Print a key to the colors used after the tree"
+    )]
+    pub legend: bool,
+
+    #[arg(
+        long = "depth-weighted",
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        help = "After the tree, list the N files (default 10) with the largest size x depth, to find big files buried deep"
+    )]
+    pub depth_weighted: Option<usize>,
+
+    #[arg(
+        long = "per-top-level",
+        default_value_t = false,
+        help = "After the tree, list each directory directly under the root with its file count and total size, largest first"
+    )]
+    pub per_top_level: bool,
+
+    #[arg(
+        long = "chart",
+        value_name = "METRIC",
+        num_args = 0..=1,
+        default_missing_value = "count",
+        help = "After the tree, chart files per extension as ASCII bars: 'count' (default) or 'size'"
+    )]
+    pub chart: Option<String>,
+
+    #[arg(
+        long = "annotate",
+        default_value_t = false,
+        help = "Record the root, time and filters used: a '# mytree' header line in text output, a meta object in JSON"
+    )]
+    pub annotate: bool,
+
+    #[arg(
+        long = "strict",
+        default_value_t = false,
+        help = "Stop at the first unreadable directory or entry instead of reporting them after the tree"
+    )]
+    pub strict: bool,
+
+    #[arg(
+        long = "strict-utf8",
+        default_value_t = false,
+        help = "Fail on the first file name that is not valid UTF-8 instead of showing it with replacement characters"
+    )]
+    pub strict_utf8: bool,
+
+    #[arg(
+        long = "fail-on-empty",
+        default_value_t = false,
+        help = "Exit with an error when no files are left after filtering"
+    )]
+    pub fail_on_empty: bool,
+
+    #[arg(
+        long = "warn-depth",
+        value_name = "N",
+        help = "Warn on stderr when the tree goes more than N levels deep"
+    )]
+    pub warn_depth: Option<usize>,
+
+    #[arg(
+        long = "max-lines",
+        value_name = "N",
+        help = "Stop printing the tree after N lines in total and note how many were left out"
+    )]
+    pub max_lines: Option<usize>,
+
+    #[arg(
+        long = "json-relative-paths",
+        default_value_t = false,
+        help = "Write JSON paths relative to the root directory, with '/' separators on every platform"
+    )]
+    pub json_relative_paths: bool,
+
+    #[arg(
+        long = "json-no-path",
+        default_value_t = false,
+        conflicts_with = "json_relative_paths",
+        help = "Omit the path field from JSON output"
+    )]
+    pub json_no_path: bool,
+
+    #[arg(
+        long = "json-children-only",
+        default_value_t = false,
+        help = "Write the root's children as the top-level JSON array, without the root node"
+    )]
+    pub json_children_only: bool,
+
+    #[arg(
+        long = "json-flat",
+        default_value_t = false,
+        help = "Write JSON as a flat array of nodes, each naming its parent by array index"
+    )]
+    pub json_flat: bool,
+
+    #[arg(
+        long = "json-raw-names",
+        default_value_t = false,
+        help = "Add the original name bytes as name_bytes to JSON nodes whose names are not valid UTF-8"
+    )]
+    pub json_raw_names: bool,
+
+    #[arg(
+        long = "json-stats",
+        default_value_t = false,
+        help = "Add file_count and total_size for the whole subtree to every directory in the JSON"
+    )]
+    pub json_stats: bool,
+
+    #[arg(
+        long = "json-indent",
+        value_name = "N",
+        default_value_t = 2,
+        help = "Spaces per indentation level in JSON output; 0 writes compact JSON"
+    )]
+    pub json_indent: usize,
+
+    #[arg(
+        long = "show-filtered-count",
+        default_value_t = false,
+        help = "Report how many entries were hidden by filters in the summary"
+    )]
+    pub show_filtered_count: bool,
+
+    #[arg(
+        long = "root-label",
+        value_name = "STR",
+        help = "Print STR as the header instead of the root path"
+    )]
+    pub root_label: Option<String>,
+
+    #[arg(
+        long = "basename-root",
+        default_value_t = false,
+        conflicts_with = "root_label",
+        help = "Print only the final component of the root path as the header"
+    )]
+    pub basename_root: bool,
+
+    #[arg(
+        long = "tilde",
+        default_value_t = false,
+        help = "Show paths under $HOME starting with ~ in the header, --paths-only and other printed paths"
+    )]
+    pub tilde: bool,
+
+    #[arg(
+        long = "dry-run",
+        default_value_t = false,
+        help = "Scan and report what exports would write without creating any files"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long = "timeout",
+        value_name = "DURATION",
+        help = "Stop scanning after DURATION (e.g. 500ms, 10s, 2m) and show the partial tree"
+    )]
+    pub timeout: Option<String>,
+}
+
+// the flags --compact sets; each one given after it on the command line wins
+const COMPACT_FLAGS: [&str; 6] = [
+    "indent",
+    "long_format",
+    "count_badge",
+    "child_count",
+    "size_inline",
+    "running_total",
+];
+
+/*
+Where --compact and the flags it covers last appeared on the command line.
+The derived parser drops argument order, so this reads it from the matches.
+*/
+#[derive(Debug, Default)]
+pub struct FlagPositions {
+    last: HashMap<String, usize>,
+}
+
+impl FlagPositions {
+    fn after_compact(&self, id: &str) -> bool {
+        match (self.last.get(id), self.last.get("compact")) {
+            (Some(flag), Some(compact)) => flag > compact,
+            _ => false,
+        }
+    }
+}
+
+impl clap::FromArgMatches for FlagPositions {
+    fn from_arg_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        let mut positions = FlagPositions::default();
+        positions.update_from_arg_matches(matches)?;
+        Ok(positions)
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &clap::ArgMatches) -> Result<(), clap::Error> {
+        for id in std::iter::once("compact").chain(COMPACT_FLAGS) {
+            if matches.value_source(id) != Some(clap::parser::ValueSource::CommandLine) {
+                continue;
+            }
+            if let Some(at) = matches.indices_of(id).and_then(|at| at.max()) {
+                self.last.insert(id.to_string(), at);
+            }
+        }
+        Ok(())
+    }
+}
+
+// no arguments of its own; it only observes the ones declared on Args
+impl clap::Args for FlagPositions {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        cmd
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        cmd
+    }
+}
+
+pub struct ScanOptions {
+    sort_by: SortBy,
+    reverse: bool,
+    pins: Vec<String>,
+    locale_sort: bool,
+    shuffle_seed: Option<u64>,
+    extension_filters: Option<HashSet<String>>,
+    case_sensitive_ext: bool,
+    show_hidden: bool,
+    no_hidden_dirs: bool,
+    regex_filters: Vec<Regex>,
+    highlight: Option<Regex>,
+    regex_mode: RegexMode,
+    context: usize,
+    include_globs: Vec<GlobPattern>,
+    exclude_globs: Vec<GlobPattern>,
+    prune_globs: Vec<GlobPattern>,
+    // --glob-root: the part of PATH after the literal directories walked
+    root_glob: Option<GlobPattern>,
+    size_limit: Option<u64>,
+    size_range: Option<SizeRange>,
+    exclude_empty_files: bool,
+    min_lines: Option<usize>,
+    changed_files: Option<ChangedFiles>,
+    recency: Option<Recency>,
+    min_depth: usize,
+    max_depth: Option<usize>,
+    collapse: bool,
+    device: Option<u64>,
+    follow_symlinks: bool,
+    dirs_only: bool,
+    only: Option<OnlyKind>,
+    sample: Option<usize>,
+    count_badge: bool,
+    child_count: bool,
+    fold_identical: bool,
+    size_inline: bool,
+    running_total: bool,
+    mark_empty: bool,
+    overview: bool,
+    indent: usize,
+    outline: bool,
+    bottom_up: bool,
+    connectors: Connectors,
+    max_name_length: Option<usize>,
+    heat: Option<Vec<Duration>>,
+    size_heat: bool,
+    depth_colors: bool,
+    long_format: bool,
+    show_inode: bool,
+    show_perms: bool,
+    align: bool,
+    dedup_links: bool,
+    no_metadata: bool,
+    write_json: Option<String>,
+    write_csv: Option<String>,
+    manifest: Option<String>,
+    compare: Option<PathBuf>,
+    svg: Option<String>,
+    sexp: Option<String>,
+    web: Option<String>,
+    #[cfg(feature = "sqlite")]
+    write_sqlite: Option<String>,
+    quiet: bool,
+    summary_json: bool,
+    summary_for: Option<PathBuf>,
+    disk: bool,
+    count_extensions: Vec<String>,
+    paths_only: bool,
+    index: bool,
+    broken_links: bool,
+    category_summary: bool,
+    breakdown: bool,
+    legend: bool,
+    depth_weighted: Option<usize>,
+    per_top_level: bool,
+    chart: Option<ChartMetric>,
+    annotate: bool,
+    strict: bool,
+    strict_utf8: bool,
+    fail_on_empty: bool,
+    warn_depth: Option<usize>,
+    max_lines: Option<usize>,
+    json_paths: JsonPathMode,
+    json_children_only: bool,
+    json_flat: bool,
+    json_raw_names: bool,
+    json_stats: bool,
+    json_indent: usize,
+    show_filtered_count: bool,
+    root_label: Option<String>,
+    basename_root: bool,
+    // --tilde: $HOME, abbreviated to ~ in printed paths
+    tilde_home: Option<PathBuf>,
+    dry_run: bool,
+    timeout: Option<Duration>,
+}
+
+#[derive(Default, Serialize)]
+struct Stats {
+    #[serde(rename = "directories")]
+    dirs: usize,
+    files: usize,
+    #[serde(rename = "bytes")]
+    size: u64,
+}
+
+#[derive(Default)]
+struct FilterStats {
+    extension: usize,
+    regex: usize,
+    include: usize,
+    exclude: usize,
+    pruned: usize,
+    other_device: usize,
+    unchanged: usize,
+    too_large: usize,
+    wrong_size: usize,
+    too_short: usize,
+    wrong_kind: usize,
+    not_recent: usize,
+}
+
+impl FilterStats {
+    fn total(&self) -> usize {
+        self.extension
+            + self.regex
+            + self.include
+            + self.exclude
+            + self.pruned
+            + self.other_device
+            + self.unchanged
+            + self.too_large
+            + self.wrong_size
+            + self.too_short
+            + self.wrong_kind
+            + self.not_recent
+    }
+}
+
+/*
+Mutable bookkeeping carried through a single walk
+*/
+#[derive(Default)]
+struct WalkState {
+    filtered: FilterStats,
+    seen_links: HashSet<(u64, u64)>,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    // sibling window for --context; only the tree builder sets it
+    context: usize,
+    // deepest level of any entry kept in the tree, for --warn-depth
+    deepest: usize,
+    // splitmix64 state for --shuffle, advanced once per entry moved
+    shuffle: Option<u64>,
+    // when set, recoverable errors are kept here instead of ending the walk
+    collect_errors: bool,
+    errors: Vec<TreeParseError>,
+}
+
+impl WalkState {
+    fn new(opts: &ScanOptions) -> Self {
+        WalkState {
+            deadline: opts.timeout.map(|t| Instant::now() + t),
+            shuffle: opts.shuffle_seed,
+            ..WalkState::default()
+        }
+    }
+
+    /*
+    Checked between entries; a blocking read_dir can still overrun the deadline
+    */
+    fn past_deadline(&mut self) -> bool {
+        if !self.timed_out && self.deadline.is_some_and(|d| Instant::now() >= d) {
+            self.timed_out = true;
+        }
+        self.timed_out
+    }
+
+    /*
+    Record an error that only costs one entry or directory, or hand it back
+    to abort the walk when errors are not being collected
+    */
+    fn recover(&mut self, err: TreeParseError) -> Result<(), ParseError> {
+        if !self.collect_errors {
+            return Err(ParseError::Tree(err));
+        }
+        self.errors.push(err);
+        Ok(())
+    }
+}
+
+/*
+Files reported by git as changed, relative to the scanned root
+*/
+struct ChangedFiles {
+    root: PathBuf,
+    base: String,
+    files: HashSet<PathBuf>,
+}
+
+struct GlobPattern {
+    raw: String,
+    regex: Regex,
+    match_path: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryMeta {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub inode: Option<u64>,
+    link_id: Option<(u64, u64)>,
+    // rejected by --extension/--regex but kept as a --context candidate
+    context: bool,
+    pub is_dir: bool,
+    pub kind: EntryKind,
+}
+
+impl EntryMeta {
+    /*
+    The name exactly as the OS returned it; `name` is a lossy UTF-8 copy
+    meant for display
+    */
+    pub fn os_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or(self.path.as_os_str())
+    }
+}
+
+/*
+Coarse file categories for --category-summary, decided by extension alone
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileCategory {
+    Code,
+    Docs,
+    Images,
+    Media,
+    Archives,
+    Data,
+    Other,
+}
+
+impl FileCategory {
+    const ALL: [FileCategory; 7] = [
+        FileCategory::Code,
+        FileCategory::Docs,
+        FileCategory::Images,
+        FileCategory::Media,
+        FileCategory::Archives,
+        FileCategory::Data,
+        FileCategory::Other,
+    ];
+
+    fn of(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "rs" | "py" | "c" | "cpp" | "h" | "hpp" | "cs" | "ml" | "mli" | "js" | "ts" | "jsx"
+            | "tsx" | "go" | "java" | "kt" | "swift" | "rb" | "php" | "sh" | "hs" | "lua"
+            | "scala" => FileCategory::Code,
+            "md" | "txt" | "rst" | "adoc" | "org" | "tex" | "pdf" | "doc" | "docx" | "odt"
+            | "rtf" => FileCategory::Docs,
+            "png" | "jpg" | "jpeg" | "gif" | "bmp" | "svg" | "webp" | "ico" | "tiff" | "heic" => {
+                FileCategory::Images
+            }
+            "mp3" | "wav" | "flac" | "ogg" | "mp4" | "mkv" | "mov" | "avi" | "webm" => {
+                FileCategory::Media
+            }
+            "zip" | "tar" | "gz" | "tgz" | "bz2" | "xz" | "7z" | "rar" | "zst" => {
+                FileCategory::Archives
+            }
+            "json" | "yaml" | "yml" | "toml" | "csv" | "xml" | "ini" | "lock" | "db" | "sqlite"
+            | "parquet" => FileCategory::Data,
+            _ => FileCategory::Other,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FileCategory::Code => "code",
+            FileCategory::Docs => "docs",
+            FileCategory::Images => "images",
+            FileCategory::Media => "media",
+            FileCategory::Archives => "archives",
+            FileCategory::Data => "data",
+            FileCategory::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    SkipSubtree,
+}
+
+#[derive(Debug, Clone)]
+pub enum SortBy {
+    Alphabetical,
+    FileSize,
+    LastUpdatedTimestamp,
+    ChildCount,
+}
+
+#[derive(Debug, Clone)]
+pub enum RegexMode {
+    All,
+    Any,
+}
+
+#[derive(Debug, Clone)]
+enum JsonPathMode {
+    Scanned,
+    Relative,
+    Omitted,
+}
+
+#[derive(Debug)]
+pub struct ArgParseError {
+    pub details: ArgParseErrorType,
+}
+
+#[derive(Debug)]
+pub enum ArgParseErrorType {
+    SortFlag(String),
+    BadExtension(String),
+    BadRegex(String),
+    RegexMode(String),
+    BadGlob(String),
+    BadDepth(String),
+    BadDuration(String),
+    BadIndent(usize),
+    BadSize(String),
+    ChartMetric(String),
+    OnlyKind(String),
+}
+
+impl fmt::Display for ArgParseErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgParseErrorType::SortFlag(flag) => write!(
+                f,
+                "invalid sort flag \"{flag}\" (expected \"fs\", \"ts\" or \"children\")"
+            ),
+            ArgParseErrorType::BadExtension(ext) => write!(f, "invalid extension \"{ext}\""),
+            ArgParseErrorType::BadRegex(msg) => write!(f, "invalid regex -> {msg}"),
+            ArgParseErrorType::RegexMode(mode) => write!(
+                f,
+                "invalid regex mode \"{mode}\" (expected \"all\" or \"any\")"
+            ),
+            ArgParseErrorType::BadGlob(msg) => write!(f, "invalid glob -> {msg}"),
+            ArgParseErrorType::BadDepth(msg) => write!(f, "invalid depth -> {msg}"),
+            ArgParseErrorType::BadDuration(raw) => write!(
+                f,
+                "invalid duration \"{raw}\" (expected e.g. 500ms, 10s, 2m or 7d)"
+            ),
+            ArgParseErrorType::BadSize(raw) => write!(
+                f,
+                "invalid size \"{raw}\" (expected bytes or e.g. 500K, 10M, 1.5G)"
+            ),
+            ArgParseErrorType::ChartMetric(metric) => write!(
+                f,
+                "invalid chart metric \"{metric}\" (expected \"count\" or \"size\")"
+            ),
+            ArgParseErrorType::OnlyKind(kind) => write!(
+                f,
+                "invalid entry kind \"{kind}\" (expected \"file\", \"dir\", \"symlink\" or \"empty\")"
+            ),
+            ArgParseErrorType::BadIndent(width) => {
+                write!(f, "invalid indent {width} (expected 2 to 8 columns)")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ArgParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "argument error -> {}", self.details)
+    }
+}
+
+impl Error for ArgParseError {}
+
+#[derive(Debug)]
+pub struct TreeParseError {
+    pub details: TreeParseType,
+}
+
+#[derive(Debug)]
+pub enum TreeParseType {
+    Io(String),
+    InvalidInput(String),
+    Git(String),
+}
+
+impl fmt::Display for TreeParseType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeParseType::Io(msg) => write!(f, "IO error -> {msg}"),
+            TreeParseType::InvalidInput(msg) => write!(f, "{msg}"),
+            TreeParseType::Git(msg) => write!(f, "git error -> {msg}"),
+        }
+    }
+}
+
+impl fmt::Display for TreeParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for TreeParseError {}
+
+impl From<io::Error> for TreeParseError {
+    fn from(e: io::Error) -> Self {
+        TreeParseError {
+            details: TreeParseType::Io(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Args(ArgParseError),
+    Tree(TreeParseError),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Args(e) => Debug::fmt(&e, f),
+            ParseError::Tree(e) => Debug::fmt(&e, f),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Args(e) => Some(e),
+            ParseError::Tree(e) => Some(e),
+        }
+    }
+}
+
+impl From<ArgParseError> for ParseError {
+    fn from(e: ArgParseError) -> Self {
+        Self::Args(e)
+    }
+}
+impl From<TreeParseError> for ParseError {
+    fn from(e: TreeParseError) -> Self {
+        Self::Tree(e)
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> io::Error {
+        io::Error::other(e)
+    }
+}
+
+// read back from --json output by --compare; unknown fields mean the file is
+// some other export (such as --json-flat) and is rejected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TreeNode {
+    name: String,
+    // the exact on-disk name when `name` had to be decoded lossily
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name_bytes: Option<Vec<u8>>,
+    // lowercased; null for directories and files without one
+    extension: Option<String>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(
+        rename = "path",
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_lossy_path"
+    )]
+    json_path: Option<PathBuf>,
+    size: u64,
+    mtime: SystemTime,
+    #[serde(skip)]
+    inode: Option<u64>,
+    is_dir: bool,
+    #[serde(skip)]
+    kind: EntryKind,
+    #[serde(skip)]
+    duplicate_link: bool,
+    #[serde(skip)]
+    context: bool,
+    #[serde(skip)]
+    empty_dir: bool,
+    // directories merged into this one by --collapse, still counted as such
+    #[serde(skip)]
+    collapsed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+    children: Option<Vec<TreeNode>>,
+}
+
+/*
+How a listing was produced, written by --annotate as a header comment or as
+the meta object beside the JSON tree
+*/
+#[derive(Debug, Serialize)]
+struct Annotation {
+    root: PathBuf,
+    generated_at: String,
+    options: Vec<String>,
+    // which shape the JSON tree has, for --compare
+    json_children_only: bool,
+}
+
+impl Annotation {
+    fn new(root: &Path, opts: &ScanOptions) -> Self {
+        Annotation {
+            root: root.to_path_buf(),
+            generated_at: Local::now().to_rfc3339(),
+            options: opts.describe(),
+            json_children_only: opts.json_children_only,
+        }
+    }
+
+    fn header(&self) -> String {
+        let mut line = format!("# mytree {}", self.root.display());
+        for flag in &self.options {
+            line.push(' ');
+            line.push_str(flag);
+        }
+        format!("{line} at {}", self.generated_at)
+    }
+}
+
+#[derive(Serialize)]
+struct Annotated<'a, T: ?Sized> {
+    meta: &'a Annotation,
+    tree: &'a T,
+}
+
+/*
+One row of the --json-flat output. parent is the index of the parent row in
+the same array, or null for a top-level node
+*/
+#[derive(Debug, Serialize)]
+struct FlatNode<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name_bytes: Option<&'a [u8]>,
+    extension: Option<&'a str>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_lossy_path"
+    )]
+    path: Option<&'a Path>,
+    #[cfg(feature = "sqlite")]
+    #[serde(skip)]
+    full_path: &'a Path,
+    parent: Option<usize>,
+    depth: usize,
+    size: u64,
+    mtime: SystemTime,
+    is_dir: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+}
+
+/*
+Split the parsed arguments into the root path and the options for the walk
+*/
+pub fn create_scan_options_from_args(args: Args) -> Result<(PathBuf, ScanOptions), ParseError> {
+    let mut root = args
+        .path
+        .clone()
+        .unwrap_or_else(|| default_root(std::env::var_os("MYTREE_ROOT")));
+    let mut root_glob = None;
+    if args.glob_root {
+        if let Some((base, pattern)) = split_glob_root(&root) {
+            root = base;
+            root_glob = Some(GlobPattern::new(&pattern)?);
+        }
+    }
+    if args.repo_root {
+        root = find_repo_root(&root)?;
+    }
+    let summary_for = match args.summary_for {
+        Some(ref raw) => Some(resolve_subtree(&root, raw)?),
+        None => None,
+    };
+    let sort_by = match args.sort_by.as_deref() {
+        Some("fs") => SortBy::FileSize,
+        Some("ts") => SortBy::LastUpdatedTimestamp,
+        Some("children") => SortBy::ChildCount,
+        Some(bad) => {
+            return Err(ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::SortFlag(bad.into()),
+            }));
+        }
+        None => SortBy::Alphabetical,
+    };
+
+    let extension_filters = match args.extension_filters {
+        Some(list) => Some(parse_extension_filters(list)?),
+        None => None,
+    };
+    let count_extensions = args
+        .count
+        .into_iter()
+        .map(normalize_extension)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut regex_filters = Vec::with_capacity(args.regex.len());
+    for pattern in args.regex {
+        regex_filters.push(compile_regex(&pattern)?);
+    }
+    let highlight = match args.highlight {
+        Some(ref pattern) => Some(compile_regex(pattern)?),
+        None => None,
+    };
+
+    let regex_mode = match args.regex_mode.as_deref() {
+        Some("all") => RegexMode::All,
+        Some("any") | None => RegexMode::Any,
+        Some(bad) => {
+            return Err(ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::RegexMode(bad.into()),
+            }));
+        }
+    };
+
+    let only = match args.only.as_deref() {
+        Some("file") => Some(OnlyKind::File),
+        Some("dir") => Some(OnlyKind::Dir),
+        Some("symlink") => Some(OnlyKind::Symlink),
+        Some("empty") => Some(OnlyKind::Empty),
+        Some(bad) => {
+            return Err(ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::OnlyKind(bad.into()),
+            }));
+        }
+        None => None,
+    };
+
+    let chart = match args.chart.as_deref() {
+        Some("count") => Some(ChartMetric::Count),
+        Some("size") => Some(ChartMetric::Size),
+        Some(bad) => {
+            return Err(ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::ChartMetric(bad.into()),
+            }));
+        }
+        None => None,
+    };
+
+    let include_globs = compile_globs(args.include)?;
+    let mut exclude = args.exclude;
+    if let Some(source) = args.exclude_from {
+        exclude.extend(load_exclude_patterns(&source)?);
+    }
+    if args.exclude_vcs {
+        exclude.extend(VCS_DIRS.iter().map(|dir| dir.to_string()));
+    }
+    let exclude_globs = compile_globs(exclude)?;
+    let prune_globs = compile_globs(args.prune_dir)?;
+
+    let base = match args.since {
+        Some(ref rev) => Some(rev.as_str()),
+        None if args.changed => Some("HEAD"),
+        None => None,
+    };
+    let changed_files = match base {
+        Some(base) => Some(ChangedFiles {
+            root: root.clone(),
+            base: base.to_string(),
+            files: git_changed_files(&root, base)?,
+        }),
+        None => None,
+    };
+
+    let heat = if args.heat {
+        let raw = args
+            .heat_buckets
+            .unwrap_or_else(|| ["1d", "7d", "30d", "365d"].map(String::from).to_vec());
+        let mut buckets = raw
+            .iter()
+            .map(|b| parse_duration(b))
+            .collect::<Result<Vec<_>, _>>()?;
+        buckets.sort();
+        Some(buckets)
+    } else {
+        None
+    };
+
+    let size_limit = match args.exclude_larger_than {
+        Some(raw) => Some(parse_size(&raw)?),
+        None => None,
+    };
+    let size_range = match args.size {
+        Some(raw) => Some(SizeRange::parse(&raw)?),
+        None => None,
+    };
+
+    let timeout = match args.timeout {
+        Some(raw) => Some(parse_duration(&raw)?),
+        None => None,
+    };
+
+    let min_depth = args.min_depth.unwrap_or(1);
+    let max_depth = if args.no_recursion {
+        Some(1)
+    } else {
+        args.max_depth
+    };
+    if let Some(max) = max_depth {
+        if max == 0 || min_depth > max {
+            return Err(ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::BadDepth(format!(
+                    "--max-depth {max} must be at least 1 and not below --min-depth {min_depth}"
+                )),
+            }));
+        }
+    }
+
+    // --compact is a preset; a flag it covers keeps its own value only when
+    // given after --compact
+    let compact = |id: &str| args.compact && !args.positions.after_compact(id);
+    let mut indent = args.indent.unwrap_or(4);
+    if !(2..=8).contains(&indent) {
+        return Err(ParseError::Args(ArgParseError {
+            details: ArgParseErrorType::BadIndent(indent),
+        }));
+    }
+    if compact("indent") {
+        indent = 2;
+    }
+    let long_format = args.long_format && !compact("long_format");
+    let count_badge = args.count_badge && !compact("count_badge");
+    let child_count = args.child_count && !compact("child_count");
+    let size_inline = args.size_inline && !compact("size_inline");
+    let running_total = args.running_total && !compact("running_total");
+
+    let anchor = if let Some(ref path) = args.stay_on {
+        Some(path)
+    } else if args.one_file_system {
+        Some(&root)
+    } else {
+        None
+    };
+    let device = match anchor {
+        Some(path) => {
+            let md = fs::metadata(path).map_err(|e| {
+                ParseError::Tree(TreeParseError {
+                    details: TreeParseType::Io(format!(
+                        "failed to read metadata for {}: {e}",
+                        path.display()
+                    )),
+                })
+            })?;
+            device_of(&md)
+        }
+        None => None,
+    };
+
+    let json_paths = if args.json_no_path {
+        JsonPathMode::Omitted
+    } else if args.json_relative_paths {
+        JsonPathMode::Relative
+    } else {
+        JsonPathMode::Scanned
+    };
+
+    let opts = ScanOptions {
+        sort_by,
+        reverse: args.reverse,
+        pins: args.pin,
+        locale_sort: args.locale_sort,
+        // an unseeded shuffle still records a seed so --annotate can replay it
+        shuffle_seed: args.shuffle.then(|| {
+            args.seed.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0)
+            })
+        }),
+        extension_filters,
+        case_sensitive_ext: args.case_sensitive_ext,
+        show_hidden: args.show_hidden,
+        no_hidden_dirs: args.no_hidden_dirs,
+        regex_filters,
+        highlight,
+        regex_mode,
+        context: args.context,
+        include_globs,
+        exclude_globs,
+        prune_globs,
+        root_glob,
+        size_limit,
+        size_range,
+        exclude_empty_files: args.exclude_empty_files,
+        min_lines: args.min_lines,
+        changed_files,
+        recency: match (args.newest, args.oldest) {
+            (Some(n), _) => Some(Recency::Newest(n)),
+            (None, Some(n)) => Some(Recency::Oldest(n)),
+            (None, None) => None,
+        },
+        min_depth,
+        max_depth,
+        collapse: args.collapse,
+        device,
+        follow_symlinks: args.follow_symlinks,
+        dirs_only: args.dirs_only || matches!(only, Some(OnlyKind::Dir)),
+        only,
+        sample: args.sample,
+        count_badge,
+        child_count,
+        fold_identical: args.fold_identical,
+        size_inline,
+        running_total,
+        mark_empty: args.mark_empty,
+        overview: args.overview,
+        indent,
+        outline: args.outline,
+        bottom_up: args.bottom_up,
+        connectors: Connectors {
+            dir_prefix: args.dir_prefix.unwrap_or_default(),
+            file_prefix: args.file_prefix.unwrap_or_default(),
+            ..Connectors::new(indent, args.outline)
+        },
+        max_name_length: args.max_name_length,
+        heat,
+        size_heat: args.size_heat,
+        depth_colors: args.depth_colors,
+        long_format,
+        show_inode: args.show_inode,
+        show_perms: args.show_perms,
+        align: args.align,
+        dedup_links: args.dedup_links,
+        no_metadata: args.no_metadata,
+        write_json: args.write_json,
+        write_csv: args.write_csv,
+        manifest: args.manifest,
+        compare: args.compare,
+        svg: args.svg,
+        sexp: args.sexp,
+        web: args.web,
+        #[cfg(feature = "sqlite")]
+        write_sqlite: args.write_sqlite,
+        quiet: args.quiet,
+        summary_json: args.summary_json,
+        summary_for,
+        disk: args.disk,
+        count_extensions,
+        paths_only: args.paths_only,
+        index: args.index,
+        broken_links: args.broken_links,
+        category_summary: args.category_summary,
+        breakdown: args.breakdown,
+        legend: args.legend,
+        depth_weighted: args.depth_weighted,
+        per_top_level: args.per_top_level,
+        chart,
+        annotate: args.annotate,
+        strict: args.strict,
+        strict_utf8: args.strict_utf8,
+        fail_on_empty: args.fail_on_empty,
+        warn_depth: args.warn_depth,
+        max_lines: args.max_lines,
+        json_paths,
+        json_children_only: args.json_children_only,
+        json_flat: args.json_flat,
+        json_raw_names: args.json_raw_names,
+        json_stats: args.json_stats,
+        json_indent: args.json_indent,
+        show_filtered_count: args.show_filtered_count,
+        root_label: args.root_label,
+        basename_root: args.basename_root,
+        tilde_home: args
+            .tilde
+            .then(|| home_dir(std::env::var_os("HOME")))
+            .flatten(),
+        dry_run: args.dry_run,
+        timeout,
+    };
+
+    Ok((root, opts))
+}
+
+/*
+The root used when no path is given: the value of $MYTREE_ROOT if set and
+non-empty, otherwise the current directory
+*/
+fn default_root(env_root: Option<OsString>) -> PathBuf {
+    env_root
+        .filter(|root| !root.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/*
+The directory --tilde abbreviates, from the value of $HOME; None when unset
+or empty
+*/
+fn home_dir(env_home: Option<OsString>) -> Option<PathBuf> {
+    env_home.filter(|home| !home.is_empty()).map(PathBuf::from)
+}
+
+/*
+Split a --glob-root PATH at its first component containing a wildcard: the
+literal directories before it become the root, the rest the pattern. None
+when there is no wildcard at all.
+*/
+fn split_glob_root(raw: &Path) -> Option<(PathBuf, String)> {
+    let components: Vec<_> = raw.components().collect();
+    let first = components
+        .iter()
+        .position(|c| c.as_os_str().to_string_lossy().contains(['*', '?', '[']))?;
+    let base: PathBuf = components[..first].iter().collect();
+    let pattern = components[first..]
+        .iter()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let base = if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    };
+    Some((base, pattern))
+}
+
+/*
+Map a --summary-for path onto the root as the walk will spell it. A relative
+path is tried against the root first, then against the working directory.
+*/
+fn resolve_subtree(root: &Path, raw: &Path) -> Result<PathBuf, ParseError> {
+    let io_err = |path: &Path, e: io::Error| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!("resolving {}: {e}", path.display())),
+        })
+    };
+
+    let canonical_root = fs::canonicalize(root).map_err(|e| io_err(root, e))?;
+    let candidate = if raw.is_relative() && root.join(raw).exists() {
+        root.join(raw)
+    } else {
+        raw.to_path_buf()
+    };
+    let canonical = fs::canonicalize(&candidate).map_err(|e| io_err(&candidate, e))?;
+    let rel = canonical.strip_prefix(&canonical_root).map_err(|_| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::InvalidInput(format!(
+                "{} is not under the root {}",
+                raw.display(),
+                root.display()
+            )),
+        })
+    })?;
+    Ok(root.join(rel))
+}
+
+fn find_node<'a>(node: &'a TreeNode, path: &Path) -> Option<&'a TreeNode> {
+    if node.path == path {
+        return Some(node);
+    }
+    node.children
+        .iter()
+        .flatten()
+        .find_map(|child| find_node(child, path))
+}
+
+/*
+Walk up from start to the nearest directory holding a .git entry (a
+directory, or a file for worktrees and submodules)
+*/
+fn find_repo_root(start: &Path) -> Result<PathBuf, ParseError> {
+    let abs = fs::canonicalize(start).map_err(|e| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!("resolving {}: {e}", start.display())),
+        })
+    })?;
+
+    abs.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            ParseError::Tree(TreeParseError {
+                details: TreeParseType::Git(format!(
+                    "no git repository found at or above {}",
+                    abs.display()
+                )),
+            })
+        })
+}
+
+fn normalize_extension(raw: String) -> Result<String, ParseError> {
+    let ext = raw.trim_start_matches('.');
+    if ext.is_empty() {
+        return Err(ParseError::Args(ArgParseError {
+            details: ArgParseErrorType::BadExtension(raw),
+        }));
+    }
+    Ok(ext.to_string())
+}
+
+const VCS_DIRS: [&str; 4] = [".git", ".hg", ".svn", ".bzr"];
+
+fn parse_extension_filters(list: Vec<String>) -> Result<HashSet<String>, ParseError> {
+    list.into_iter().map(normalize_extension).collect()
+}
+
+/*
+Filters keep the case they were given in, so folding happens here and the
+case-sensitive toggle can be set before or after the extensions
+*/
+fn matches_extension(filters: &HashSet<String>, ext: &str, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        filters.contains(ext)
+    } else {
+        filters.iter().any(|f| f.eq_ignore_ascii_case(ext))
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex, ParseError> {
+    Regex::new(pattern).map_err(|e| {
+        ParseError::Args(ArgParseError {
+            details: ArgParseErrorType::BadRegex(format!("invalid regex \"{pattern}\": {e}")),
+        })
+    })
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            sort_by: SortBy::Alphabetical,
+            reverse: false,
+            pins: Vec::new(),
+            locale_sort: false,
+            shuffle_seed: None,
+            extension_filters: None,
+            case_sensitive_ext: false,
+            show_hidden: false,
+            no_hidden_dirs: false,
+            regex_filters: Vec::new(),
+            highlight: None,
+            regex_mode: RegexMode::Any,
+            context: 0,
+            include_globs: Vec::new(),
+            exclude_globs: Vec::new(),
+            prune_globs: Vec::new(),
+            root_glob: None,
+            size_limit: None,
+            size_range: None,
+            exclude_empty_files: false,
+            min_lines: None,
+            changed_files: None,
+            recency: None,
+            min_depth: 1,
+            max_depth: None,
+            collapse: false,
+            device: None,
+            follow_symlinks: false,
+            dirs_only: false,
+            only: None,
+            sample: None,
+            count_badge: false,
+            child_count: false,
+            fold_identical: false,
+            size_inline: false,
+            running_total: false,
+            mark_empty: false,
+            overview: false,
+            indent: 4,
+            outline: false,
+            bottom_up: false,
+            connectors: Connectors::new(4, false),
+            max_name_length: None,
+            heat: None,
+            size_heat: false,
+            depth_colors: false,
+            long_format: false,
+            show_inode: false,
+            show_perms: false,
+            align: false,
+            dedup_links: false,
+            no_metadata: false,
+            write_json: None,
+            write_csv: None,
+            manifest: None,
+            compare: None,
+            svg: None,
+            sexp: None,
+            web: None,
+            #[cfg(feature = "sqlite")]
+            write_sqlite: None,
+            quiet: false,
+            summary_json: false,
+            summary_for: None,
+            disk: false,
+            count_extensions: Vec::new(),
+            paths_only: false,
+            index: false,
+            broken_links: false,
+            category_summary: false,
+            breakdown: false,
+            legend: false,
+            depth_weighted: None,
+            per_top_level: false,
+            chart: None,
+            annotate: false,
+            strict: false,
+            strict_utf8: false,
+            fail_on_empty: false,
+            warn_depth: None,
+            max_lines: None,
+            json_paths: JsonPathMode::Scanned,
+            json_children_only: false,
+            json_flat: false,
+            json_raw_names: false,
+            json_stats: false,
+            json_indent: 2,
+            show_filtered_count: false,
+            root_label: None,
+            basename_root: false,
+            tilde_home: None,
+            dry_run: false,
+            timeout: None,
+        }
+    }
+}
+
+/*
+Setters for configuring a scan from code rather than through Args
+*/
+impl ScanOptions {
+    pub fn sort_by(mut self, sort_by: SortBy) -> Self {
+        self.sort_by = sort_by;
+        self
+    }
+
+    pub fn reverse(mut self, enabled: bool) -> Self {
+        self.reverse = enabled;
+        self
+    }
+
+    pub fn pin(mut self, name: impl Into<String>) -> Self {
+        self.pins.push(name.into());
+        self
+    }
+
+    pub fn locale_sort(mut self, enabled: bool) -> Self {
+        self.locale_sort = enabled;
+        self
+    }
+
+    pub fn show_hidden(mut self, enabled: bool) -> Self {
+        self.show_hidden = enabled;
+        self
+    }
+
+    pub fn no_hidden_dirs(mut self, enabled: bool) -> Self {
+        self.no_hidden_dirs = enabled;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.follow_symlinks = enabled;
+        self
+    }
+
+    pub fn extensions<I, S>(mut self, extensions: I) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let list = extensions.into_iter().map(Into::into).collect();
+        self.extension_filters = Some(parse_extension_filters(list)?);
+        Ok(self)
+    }
+
+    pub fn case_sensitive_ext(mut self, enabled: bool) -> Self {
+        self.case_sensitive_ext = enabled;
+        self
+    }
+
+    pub fn regex(mut self, pattern: &str) -> Result<Self, ParseError> {
+        self.regex_filters.push(compile_regex(pattern)?);
+        Ok(self)
+    }
+
+    pub fn regex_mode(mut self, mode: RegexMode) -> Self {
+        self.regex_mode = mode;
+        self
+    }
+
+    pub fn context(mut self, siblings: usize) -> Self {
+        self.context = siblings;
+        self
+    }
+
+    pub fn include(mut self, glob: &str) -> Result<Self, ParseError> {
+        self.include_globs.push(GlobPattern::new(glob)?);
+        Ok(self)
+    }
+
+    pub fn exclude(mut self, glob: &str) -> Result<Self, ParseError> {
+        self.exclude_globs.push(GlobPattern::new(glob)?);
+        Ok(self)
+    }
+
+    pub fn prune_dir(mut self, glob: &str) -> Result<Self, ParseError> {
+        self.prune_globs.push(GlobPattern::new(glob)?);
+        Ok(self)
+    }
+
+    pub fn exclude_larger_than(mut self, bytes: Option<u64>) -> Self {
+        self.size_limit = bytes;
+        self
+    }
+
+    pub fn size(mut self, expr: &str) -> Result<Self, ParseError> {
+        self.size_range = Some(SizeRange::parse(expr)?);
+        Ok(self)
+    }
+
+    pub fn exclude_empty_files(mut self, enabled: bool) -> Self {
+        self.exclude_empty_files = enabled;
+        self
+    }
+
+    pub fn min_lines(mut self, lines: Option<usize>) -> Self {
+        self.min_lines = lines;
+        self
+    }
+
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth.max(1);
+        self
+    }
+
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn indent(mut self, width: usize) -> Result<Self, ParseError> {
+        if !(2..=8).contains(&width) {
+            return Err(ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::BadIndent(width),
+            }));
+        }
+        self.indent = width;
+        self.connectors.restyle(self.indent, self.outline);
+        Ok(self)
+    }
+
+    pub fn outline(mut self, enabled: bool) -> Self {
+        self.outline = enabled;
+        self.connectors.restyle(self.indent, self.outline);
+        self
+    }
+
+    pub fn bottom_up(mut self, enabled: bool) -> Self {
+        self.bottom_up = enabled;
+        self
+    }
+
+    pub fn dir_prefix(mut self, prefix: &str) -> Self {
+        self.connectors.dir_prefix = prefix.to_string();
+        self
+    }
+
+    pub fn file_prefix(mut self, prefix: &str) -> Self {
+        self.connectors.file_prefix = prefix.to_string();
+        self
+    }
+}
+
+impl ScanOptions {
+    /*
+    The sort and filter settings as command-line flags, for --annotate
+    */
+    fn describe(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        match self.sort_by {
+            SortBy::Alphabetical => {}
+            SortBy::FileSize => flags.push("--sort fs".to_string()),
+            SortBy::LastUpdatedTimestamp => flags.push("--sort ts".to_string()),
+            SortBy::ChildCount => flags.push("--sort children".to_string()),
+        }
+        if let Some(seed) = self.shuffle_seed {
+            flags.push(format!("--shuffle --seed {seed}"));
+        }
+        if self.reverse {
+            flags.push("--reverse".into());
+        }
+        if self.show_hidden {
+            flags.push("--all".into());
+        }
+        if let Some(ref exts) = self.extension_filters {
+            let mut exts: Vec<_> = exts.iter().map(String::as_str).collect();
+            exts.sort_unstable();
+            flags.push(format!("--extension {}", exts.join(" ")));
+        }
+        for re in &self.regex_filters {
+            flags.push(format!("--regex {}", re.as_str()));
+        }
+        if self.regex_filters.len() > 1 && matches!(self.regex_mode, RegexMode::All) {
+            flags.push("--regex-mode all".into());
+        }
+        for glob in &self.include_globs {
+            flags.push(format!("--include {}", glob.raw));
+        }
+        for glob in &self.exclude_globs {
+            flags.push(format!("--exclude {}", glob.raw));
+        }
+        for glob in &self.prune_globs {
+            flags.push(format!("--prune-dir {}", glob.raw));
+        }
+        if let Some(ref changed) = self.changed_files {
+            flags.push(format!("--since {}", changed.base));
+        }
+        match self.recency {
+            Some(Recency::Newest(n)) => flags.push(format!("--newest {n}")),
+            Some(Recency::Oldest(n)) => flags.push(format!("--oldest {n}")),
+            None => {}
+        }
+        if let Some(limit) = self.size_limit {
+            flags.push(format!("--exclude-larger-than {limit}"));
+        }
+        if let Some(ref range) = self.size_range {
+            flags.push(format!("--size {}", range.raw));
+        }
+        if self.exclude_empty_files {
+            flags.push("--exclude-empty-files".into());
+        }
+        if let Some(lines) = self.min_lines {
+            flags.push(format!("--min-lines {lines}"));
+        }
+        if self.min_depth > 1 {
+            flags.push(format!("--min-depth {}", self.min_depth));
+        }
+        if let Some(max) = self.max_depth {
+            flags.push(format!("--max-depth {max}"));
+        }
+        if self.dirs_only {
+            flags.push("--dirs-only".into());
+        }
+        flags
+    }
+}
+
+/*
+Return a vector of ordered row-level entries at a point in the directory
+*/
+fn create_ordered_row_level_entries(
+    path: &Path,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Result<Vec<EntryMeta>, ParseError> {
+    if state.past_deadline() {
+        return Ok(Vec::new());
+    }
+
+    let iter = match fs::read_dir(path) {
+        Ok(iter) => iter,
+        Err(e) => {
+            state.recover(TreeParseError {
+                details: TreeParseType::Io(format!(
+                    "error reading directory {}: {e}",
+                    path.display()
+                )),
+            })?;
+            return Ok(Vec::new());
+        }
+    };
+
+    let mut meta_entries = Vec::new(); // allocate lazily
+
+    for dir_entry in iter {
+        if state.past_deadline() {
+            break;
+        }
+
+        let entry = match dir_entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                state.recover(TreeParseError {
+                    details: TreeParseType::Io(format!(
+                        "error reading an entry in {}: {e}",
+                        path.display()
+                    )),
+                })?;
+                continue;
+            }
+        };
+
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                state.recover(TreeParseError {
+                    details: TreeParseType::InvalidInput(format!(
+                        "could not determine file type for {}: {e}",
+                        entry.path().display()
+                    )),
+                })?;
+                continue;
+            }
+        };
+
+        if opts.strict_utf8 && entry.file_name().to_str().is_none() {
+            return Err(ParseError::Tree(TreeParseError {
+                details: TreeParseType::InvalidInput(format!(
+                    "{:?} is not a valid UTF-8 name",
+                    entry.path()
+                )),
+            }));
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let target_md = followed_dir_metadata(&entry.path(), &file_type, opts);
+        let is_dir = file_type.is_dir() || target_md.is_some();
+        let entry_path = entry.path();
+        let Some(context) = name_filter(&name, &entry_path, is_dir, opts, state) else {
+            continue;
+        };
+
+        let md = match target_md {
+            Some(md) => Some(md),
+            // --no-metadata: the file type from read_dir is all we need
+            None if opts.no_metadata => None,
+            None => match entry.metadata() {
+                Ok(md) => Some(md),
+                Err(e) => {
+                    state.recover(TreeParseError {
+                        details: TreeParseType::Io(format!(
+                            "failed to read metadata for {}: {e}",
+                            entry.path().display()
+                        )),
+                    })?;
+                    continue;
+                }
+            },
+        };
+        let size = md.as_ref().map_or(0, fs::Metadata::len);
+
+        if opts
+            .device
+            .is_some_and(|dev| md.as_ref().and_then(device_of) != Some(dev))
+        {
+            state.filtered.other_device += 1;
+            continue;
+        }
+        let kind = EntryKind::from_file_type(&file_type);
+        let lines = || count_text_lines(&entry_path);
+        if !metadata_filter(is_dir, kind, size, lines, opts, &mut state.filtered) {
+            continue;
+        }
+
+        meta_entries.push(EntryMeta {
+            name,
+            path: entry.path(),
+            size,
+            mtime: md
+                .as_ref()
+                .and_then(|md| md.modified().ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            inode: md.as_ref().and_then(inode_of),
+            link_id: md.as_ref().filter(|_| !is_dir).and_then(hard_link_id),
+            context,
+            is_dir,
+            kind,
+        });
+    }
+
+    Ok(order_meta_entries(meta_entries, opts, state))
+}
+
+/*
+The filters that need only an entry's name and path, checked before its
+metadata is read. None when the entry is dropped; otherwise whether it is
+only kept as a --context candidate.
+*/
+fn name_filter(
+    name: &str,
+    path: &Path,
+    is_dir: bool,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Option<bool> {
+    if name.starts_with('.') && (!opts.show_hidden || (opts.no_hidden_dirs && is_dir)) {
+        return None;
+    }
+    if opts.exclude_globs.iter().any(|g| g.matches(name, path)) {
+        state.filtered.exclude += 1;
+        return None;
+    }
+    if is_dir {
+        if opts.prune_globs.iter().any(|g| g.matches(name, path)) {
+            state.filtered.pruned += 1;
+            return None;
+        }
+        return Some(false);
+    }
+
+    // context entries stay counted as filtered even when shown
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+    let mut context = false;
+    if opts
+        .extension_filters
+        .as_ref()
+        .is_some_and(|set| !matches_extension(set, ext, opts.case_sensitive_ext))
+    {
+        state.filtered.extension += 1;
+        context = true;
+    } else if !matches_regex_filters(name, &opts.regex_filters, &opts.regex_mode) {
+        state.filtered.regex += 1;
+        context = true;
+    }
+    if context && state.context == 0 {
+        return None;
+    }
+    if !opts.include_globs.is_empty() && !opts.include_globs.iter().any(|g| g.matches(name, path)) {
+        state.filtered.include += 1;
+        return None;
+    }
+    if opts
+        .changed_files
+        .as_ref()
+        .is_some_and(|changed| !changed.contains(path))
+    {
+        state.filtered.unchanged += 1;
+        return None;
+    }
+    Some(context)
+}
+
+/*
+The filters on a file's size and kind. lines is only called for --min-lines
+and only on regular files, since counting means opening and reading the
+entry; a FIFO or device would block. Every other kind fails --min-lines.
+*/
+fn metadata_filter(
+    is_dir: bool,
+    kind: EntryKind,
+    size: u64,
+    lines: impl FnOnce() -> Option<usize>,
+    opts: &ScanOptions,
+    filtered: &mut FilterStats,
+) -> bool {
+    if is_dir {
+        return true;
+    }
+    if opts.size_limit.is_some_and(|limit| size > limit) {
+        filtered.too_large += 1;
+        return false;
+    }
+    if opts.size_range.as_ref().is_some_and(|r| !r.contains(size))
+        || opts.exclude_empty_files && size == 0
+    {
+        filtered.wrong_size += 1;
+        return false;
+    }
+    if opts
+        .min_lines
+        .is_some_and(|min| kind != EntryKind::File || !matches!(lines(), Some(n) if n >= min))
+    {
+        filtered.too_short += 1;
+        return false;
+    }
+    if opts.only.is_some_and(|only| !only.matches(kind, size)) {
+        filtered.wrong_kind += 1;
+        return false;
+    }
+    true
+}
+
+/*
+Put one directory's surviving entries in sibling order: sorted or shuffled,
+reversed, pinned, then cut down to the --context window
+*/
+fn order_meta_entries(
+    meta_entries: Vec<EntryMeta>,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Vec<EntryMeta> {
+    let mut sorted = match state.shuffle.as_mut() {
+        Some(rng) => shuffle_meta_entries(meta_entries, rng),
+        None => sort_meta_entries(meta_entries, &opts.sort_by, opts.locale_sort),
+    };
+    if opts.reverse {
+        sorted.reverse();
+    }
+    let entries = pin_meta_entries(sorted, &opts.pins);
+    if state.context > 0 {
+        keep_context_window(entries, state.context)
+    } else {
+        entries
+    }
+}
+
+/*
+Drop context candidates that are not within window positions of a file that
+passed the filters, in final sibling order
+*/
+fn keep_context_window(entries: Vec<EntryMeta>, window: usize) -> Vec<EntryMeta> {
+    let mut keep = vec![false; entries.len()];
+    for (i, entry) in entries.iter().enumerate() {
+        if !entry.is_dir && !entry.context {
+            let end = (i + window + 1).min(entries.len());
+            keep[i.saturating_sub(window)..end].fill(true);
+        }
+    }
+
+    entries
+        .into_iter()
+        .zip(keep)
+        .filter(|(entry, keep)| !entry.context || *keep)
+        .map(|(entry, _)| entry)
+        .collect()
+}
+
+/*
+With --follow-symlinks, return the target metadata of a symlink that points
+at a directory. A link to one of the directories it sits inside (resolved
+along the path it was reached by) is left as a leaf, which is what stops
+symlink cycles; the same directory reached through two unrelated links is
+listed under both.
+*/
+fn followed_dir_metadata(
+    path: &Path,
+    file_type: &fs::FileType,
+    opts: &ScanOptions,
+) -> Option<fs::Metadata> {
+    if !opts.follow_symlinks || !file_type.is_symlink() {
+        return None;
+    }
+    let md = fs::metadata(path).ok().filter(|md| md.is_dir())?;
+    let real = fs::canonicalize(path).ok()?;
+    let cycle = path
+        .ancestors()
+        .skip(1)
+        .any(|dir| fs::canonicalize(dir).is_ok_and(|dir| dir == real));
+    (!cycle).then_some(md)
+}
+
+impl GlobPattern {
+    /*
+    Translate a shell glob into an anchored regex. Patterns containing a
+    '/' are matched against the trailing components of the entry path,
+    all others against the entry name alone.
+    */
+    fn new(raw: &str) -> Result<Self, ParseError> {
+        let bad = |why: &str| {
+            ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::BadGlob(format!("invalid glob \"{raw}\": {why}")),
+            })
+        };
+
+        let match_path = raw.contains('/');
+        let mut re = String::from(if match_path { "(?:^|/)" } else { "^" });
+        let mut chars = raw.trim_start_matches('/').chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                }
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                '[' => {
+                    let mut class = String::from("[");
+                    if matches!(chars.peek(), Some('!') | Some('^')) {
+                        chars.next();
+                        class.push('^');
+                    }
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            closed = true;
+                            break;
+                        }
+                        if c == '\\' || c == '[' {
+                            class.push('\\');
+                        }
+                        class.push(c);
+                    }
+                    if !closed {
+                        return Err(bad("unclosed character class"));
+                    }
+                    class.push(']');
+                    re.push_str(&class);
+                }
+                _ => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+
+        let regex = Regex::new(&re).map_err(|e| bad(&e.to_string()))?;
+        Ok(GlobPattern {
+            raw: raw.to_string(),
+            regex,
+            match_path,
+        })
+    }
+
+    // the whole of a path relative to the --glob-root base, not just its tail
+    fn matches_relative(&self, rel: &str) -> bool {
+        self.regex.find(rel).is_some_and(|m| m.start() == 0)
+    }
+
+    fn matches(&self, name: &str, path: &Path) -> bool {
+        if self.match_path {
+            let path = path.to_string_lossy().replace('\\', "/");
+            self.regex.is_match(&path)
+        } else {
+            self.regex.is_match(name)
+        }
+    }
+}
+
+fn load_exclude_patterns(source: &Path) -> Result<Vec<String>, ParseError> {
+    let read_err = |e: io::Error| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!(
+                "reading exclude patterns from {}: {e}",
+                source.display()
+            )),
+        })
+    };
+
+    if source == Path::new("-") {
+        read_exclude_patterns(io::stdin().lock()).map_err(read_err)
+    } else {
+        let file = fs::File::open(source).map_err(read_err)?;
+        read_exclude_patterns(io::BufReader::new(file)).map_err(read_err)
+    }
+}
+
+/*
+Collect one pattern per line, skipping blank lines and '#' comments
+*/
+fn read_exclude_patterns<R: io::BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut patterns = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let pattern = line.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            continue;
+        }
+        patterns.push(pattern.to_string());
+    }
+    Ok(patterns)
+}
+
+impl ChangedFiles {
+    fn contains(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.root)
+            .is_ok_and(|rel| self.files.contains(rel))
+    }
+}
+
+/*
+Ask git for the files under root that differ from base in either the index
+or the working tree, relative to root
+*/
+fn git_changed_files(root: &Path, base: &str) -> Result<HashSet<PathBuf>, ParseError> {
+    let git_err = |msg: String| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Git(msg),
+        })
+    };
+
+    let inside = std::process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .map_err(|e| git_err(format!("could not run git: {e}")))?;
+    if !inside.status.success() {
+        return Err(git_err(format!(
+            "{} is not inside a git repository; git filters require one",
+            root.display()
+        )));
+    }
+
+    // resolve the ref up front so a typo gets a clearer error than git diff's
+    let verify = std::process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{base}^{{commit}}"))
+        .output()
+        .map_err(|e| git_err(format!("could not run git: {e}")))?;
+    if !verify.status.success() {
+        return Err(git_err(format!(
+            "\"{base}\" does not name a commit, branch or tag in this repository"
+        )));
+    }
+
+    let diff = std::process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["diff", "--name-only", "-z", "--relative", base, "--"])
+        .output()
+        .map_err(|e| git_err(format!("could not run git: {e}")))?;
+    if !diff.status.success() {
+        return Err(git_err(format!(
+            "git diff against {base} failed: {}",
+            String::from_utf8_lossy(&diff.stderr).trim()
+        )));
+    }
+
+    Ok(diff
+        .stdout
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(String::from_utf8_lossy(name).into_owned()))
+        .collect())
+}
+
+fn parse_duration(raw: &str) -> Result<Duration, ParseError> {
+    let bad = || {
+        ParseError::Args(ArgParseError {
+            details: ArgParseErrorType::BadDuration(raw.to_string()),
+        })
+    };
+
+    let trimmed = raw.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let value: f64 = number.parse().map_err(|_| bad())?;
+    let secs = match unit.trim() {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        "d" => value * 86400.0,
+        "w" => value * 604800.0,
+        _ => return Err(bad()),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| bad())
+}
+
+/*
+Parse a byte count with an optional binary unit suffix (K, M, G, T, with or
+without a trailing B)
+*/
+fn parse_size(raw: &str) -> Result<u64, ParseError> {
+    let bad = || {
+        ParseError::Args(ArgParseError {
+            details: ArgParseErrorType::BadSize(raw.to_string()),
+        })
+    };
+
+    let trimmed = raw.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let value: f64 = number.parse().map_err(|_| bad())?;
+    let scale: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(bad()),
+    };
+    Ok((value * scale as f64) as u64)
+}
+
+/*
+--only: directories are still walked for files of the chosen kind, and
+`dir` itself is handled as --dirs-only
+*/
+#[derive(Debug, Clone, Copy)]
+enum OnlyKind {
+    File,
+    Dir,
+    Symlink,
+    Empty,
+}
+
+impl OnlyKind {
+    fn matches(self, kind: EntryKind, size: u64) -> bool {
+        match self {
+            OnlyKind::File => kind == EntryKind::File,
+            // files still decide which directories are kept; the view hides them
+            OnlyKind::Dir => true,
+            OnlyKind::Symlink => kind == EntryKind::Symlink,
+            OnlyKind::Empty => kind == EntryKind::File && size == 0,
+        }
+    }
+}
+
+/*
+Number of lines in a text file, counting a last line without a newline;
+None for binary files (any NUL byte) and files that cannot be read
+*/
+fn count_text_lines(path: &Path) -> Option<usize> {
+    count_lines_in(fs::File::open(path).ok()?)
+}
+
+fn count_lines_in(mut reader: impl Read) -> Option<usize> {
+    let mut buf = vec![0; 64 * 1024];
+    let (mut lines, mut last) = (0, b'\n');
+    loop {
+        let n = reader.read(&mut buf).ok()?;
+        if n == 0 {
+            break;
+        }
+        if buf[..n].contains(&0) {
+            return None;
+        }
+        lines += buf[..n].iter().filter(|&&b| b == b'\n').count();
+        last = buf[n - 1];
+    }
+    Some(if last == b'\n' { lines } else { lines + 1 })
+}
+
+/*
+A --size expression as inclusive bounds: "+N" is more than N, "-N" less
+than N, "A..B" from A to B with either end optional, and a bare size exact
+*/
+#[derive(Debug, Clone)]
+struct SizeRange {
+    min: Option<u64>,
+    max: Option<u64>,
+    raw: String,
+}
+
+impl SizeRange {
+    fn parse(raw: &str) -> Result<Self, ParseError> {
+        let bad = || {
+            ParseError::Args(ArgParseError {
+                details: ArgParseErrorType::BadSize(raw.to_string()),
+            })
+        };
+        let size = |part: &str| parse_size(part).map_err(|_| bad());
+        let bound = |part: &str| -> Result<Option<u64>, ParseError> {
+            if part.trim().is_empty() {
+                Ok(None)
+            } else {
+                size(part).map(Some)
+            }
+        };
+
+        let trimmed = raw.trim();
+        let (min, max) = if let Some(more) = trimmed.strip_prefix('+') {
+            (Some(size(more)?.saturating_add(1)), None)
+        } else if let Some(less) = trimmed.strip_prefix('-') {
+            // nothing is smaller than zero bytes
+            let limit = size(less)?.checked_sub(1).ok_or_else(bad)?;
+            (None, Some(limit))
+        } else if let Some((low, high)) = trimmed.split_once("..") {
+            (bound(low)?, bound(high)?)
+        } else {
+            let exact = size(trimmed)?;
+            (Some(exact), Some(exact))
+        };
+        Ok(SizeRange {
+            min,
+            max,
+            raw: raw.to_string(),
+        })
+    }
+
+    fn contains(&self, bytes: u64) -> bool {
+        (self.min.unwrap_or(0)..=self.max.unwrap_or(u64::MAX)).contains(&bytes)
+    }
+}
+
+fn compile_globs(raw: Vec<String>) -> Result<Vec<GlobPattern>, ParseError> {
+    raw.iter().map(|g| GlobPattern::new(g)).collect()
+}
+
+impl EntryKind {
+    fn from_file_type(file_type: &fs::FileType) -> Self {
+        if file_type.is_dir() {
+            return EntryKind::Dir;
+        }
+        if file_type.is_symlink() {
+            return EntryKind::Symlink;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return EntryKind::Fifo;
+            }
+            if file_type.is_socket() {
+                return EntryKind::Socket;
+            }
+            if file_type.is_block_device() {
+                return EntryKind::BlockDevice;
+            }
+            if file_type.is_char_device() {
+                return EntryKind::CharDevice;
+            }
+        }
+        EntryKind::File
+    }
+
+    fn indicator(self) -> &'static str {
+        match self {
+            EntryKind::File | EntryKind::Dir => "",
+            EntryKind::Symlink => "@",
+            EntryKind::Fifo => " [fifo]",
+            EntryKind::Socket => " [socket]",
+            EntryKind::BlockDevice => " [block device]",
+            EntryKind::CharDevice => " [char device]",
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device_of(md: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(md.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_md: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+fn extension_of(path: &Path, is_dir: bool) -> Option<String> {
+    if is_dir {
+        return None;
+    }
+    path.extension().map(|e| e.to_string_lossy().to_lowercase())
+}
+
+/*
+serde refuses paths that are not valid UTF-8, which would fail the whole
+export; write them lossily and leave exactness to --json-raw-names
+*/
+fn serialize_lossy_path<P, S>(path: &Option<P>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    P: AsRef<Path>,
+    S: serde::Serializer,
+{
+    match path {
+        Some(path) => serializer.serialize_str(&path.as_ref().to_string_lossy()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/*
+The bytes of a file name that is not valid UTF-8, for --json-raw-names.
+Only Unix names are arbitrary bytes, so elsewhere there is nothing to keep.
+*/
+#[cfg(unix)]
+fn raw_name_bytes(path: &Path, opts: &ScanOptions) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    let name = path.file_name()?;
+    (opts.json_raw_names && name.to_str().is_none()).then(|| name.as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn raw_name_bytes(_path: &Path, _opts: &ScanOptions) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(unix)]
+fn inode_of(md: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(md.ino())
+}
+
+#[cfg(not(unix))]
+fn inode_of(_md: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/*
+Return the (device, inode) pair identifying a file's data when other hard
+links to it exist
+*/
+#[cfg(unix)]
+fn hard_link_id(md: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (md.nlink() > 1).then(|| (md.dev(), md.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_md: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+fn matches_regex_filters(name: &str, filters: &[Regex], mode: &RegexMode) -> bool {
+    if filters.is_empty() {
+        return true;
+    }
+    match mode {
+        RegexMode::All => filters.iter().all(|re| re.is_match(name)),
+        RegexMode::Any => filters.iter().any(|re| re.is_match(name)),
+    }
+}
+
+fn sort_meta_entries(
+    mut meta_entries: Vec<EntryMeta>,
+    sort_criteria: &SortBy,
+    locale_sort: bool,
+) -> Vec<EntryMeta> {
+    match sort_criteria {
+        // Unicode collation (CLDR root order), so "é" and a decomposed
+        // "e\u{301}" both sort with "e"; byte order breaks ties
+        SortBy::Alphabetical if locale_sort => {
+            let mut collator = Collator::default();
+            meta_entries.sort_by(|a, b| collator.collate(a.name.as_str(), b.name.as_str()));
+        }
+        // child counts are only known once the directories are read
+        SortBy::Alphabetical | SortBy::ChildCount => {
+            meta_entries.sort_by_key(|a| a.name.to_lowercase());
+        }
+        SortBy::FileSize => {
+            meta_entries.sort_by_key(|a| a.size);
+        }
+        SortBy::LastUpdatedTimestamp => {
+            meta_entries.sort_by_key(|a| std::cmp::Reverse(a.mtime));
+        }
+    }
+    meta_entries
+}
+
+/*
+Fisher-Yates over a splitmix64 stream, so a given seed always yields the
+same order for the same directory contents
+*/
+fn shuffle_meta_entries(mut meta_entries: Vec<EntryMeta>, rng: &mut u64) -> Vec<EntryMeta> {
+    // read_dir order is unspecified, so start from a fixed order
+    meta_entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for i in (1..meta_entries.len()).rev() {
+        *rng = rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        meta_entries.swap(i, (z % (i as u64 + 1)) as usize);
+    }
+    meta_entries
+}
+
+/*
+Entries are sorted before their directories are read, when a directory's
+size is still its own inode size and its entries are unknown. Once the
+children are built, re-sort so a directory ranks by the files beneath it
+(--sort fs) or by how many entries it holds, most first (--sort children);
+pinned entries keep the front.
+*/
+fn order_built_nodes(nodes: &mut [TreeNode], opts: &ScanOptions) {
+    if !matches!(opts.sort_by, SortBy::FileSize | SortBy::ChildCount) {
+        return;
+    }
+    let pinned = nodes
+        .iter()
+        .take_while(|n| opts.pins.contains(&n.name))
+        .count();
+    let rest = &mut nodes[pinned..];
+    if matches!(opts.sort_by, SortBy::ChildCount) {
+        // stable, so equal counts (every file) stay alphabetical
+        rest.sort_by_key(|n| std::cmp::Reverse(direct_children(n)));
+    } else {
+        rest.sort_by_cached_key(|n| {
+            if n.is_dir {
+                let mut subtree = Stats::default();
+                accumulate_subtree(&mut subtree, n);
+                subtree.size
+            } else {
+                n.size
+            }
+        });
+    }
+    if opts.reverse {
+        rest.reverse();
+    }
+}
+
+/*
+Move pinned entries to the front in pin order, leaving the rest as sorted
+*/
+fn pin_meta_entries(mut meta_entries: Vec<EntryMeta>, pins: &[String]) -> Vec<EntryMeta> {
+    if pins.is_empty() {
+        return meta_entries;
+    }
+
+    let mut pinned = Vec::with_capacity(meta_entries.len());
+    for pin in pins {
+        if let Some(idx) = meta_entries.iter().position(|e| &e.name == pin) {
+            pinned.push(meta_entries.remove(idx));
+        }
+    }
+    pinned.extend(meta_entries);
+    pinned
+}
+
+/*
+Return a vector of ordered row-level entries at a point in the directory
+*/
+fn build_directory_tree(
+    root_path: &Path,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Result<TreeNode, ParseError> {
+    let md = fs::metadata(root_path).map_err(|e| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!(
+                "failed to read metadata for {}: {e}",
+                root_path.display()
+            )),
+        })
+    })?;
+
+    state.context = opts.context;
+    let kids = match ArchiveFormat::detect(root_path, &md) {
+        Some(format) => archive_children(root_path, format, opts, state)?,
+        None => {
+            let entries = create_ordered_row_level_entries(root_path, opts, state)?;
+            let mut kids = Vec::with_capacity(entries.len());
+            for entry in entries {
+                if let Some(node) =
+                    build_tree_node_from_entry_meta(entry, root_path, 1, opts, state)?
+                {
+                    kids.push(node);
+                }
+            }
+            order_built_nodes(&mut kids, opts);
+            kids
+        }
+    };
+    let mut kids = hoist_above_min_depth(kids, 1, opts.min_depth);
+    if opts.collapse {
+        collapse_single_child_dirs(&mut kids);
+    }
+
+    let mut root = TreeNode {
+        name: root_path
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| root_path.display().to_string()),
+        name_bytes: raw_name_bytes(root_path, opts),
+        extension: None,
+        path: root_path.to_owned(),
+        json_path: json_path_for(root_path, root_path, &opts.json_paths),
+        size: md.len(),
+        mtime: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        inode: inode_of(&md),
+        is_dir: true,
+        kind: EntryKind::Dir,
+        duplicate_link: false,
+        context: false,
+        empty_dir: false,
+        collapsed: 0,
+        file_count: None,
+        total_size: None,
+        children: Some(kids),
+    };
+    if let Some(recency) = opts.recency {
+        let keep = pick_by_mtime(&root, recency);
+        retain_files(&mut root, &keep, &mut state.filtered);
+    }
+    if opts.json_stats {
+        fill_subtree_stats(&mut root);
+    }
+    Ok(root)
+}
+
+fn build_tree_node_from_entry_meta(
+    entry: EntryMeta,
+    root: &Path,
+    depth: usize,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Result<Option<TreeNode>, ParseError> {
+    let at_depth_limit = opts.max_depth.is_some_and(|max| depth >= max);
+    let children = if entry.is_dir && at_depth_limit {
+        Some(Vec::new())
+    } else if entry.is_dir {
+        let subs = create_ordered_row_level_entries(&entry.path, opts, state)?;
+        let mut nodes = Vec::with_capacity(subs.len());
+        for sub in subs {
+            if let Some(child) = build_tree_node_from_entry_meta(sub, root, depth + 1, opts, state)?
+            {
+                nodes.push(child);
+            }
+        }
+        order_built_nodes(&mut nodes, opts);
+        Some(nodes)
+    } else {
+        None
+    };
+
+    let bare = |path: &Path| fs::read_dir(path).is_ok_and(|mut dir| dir.next().is_none());
+    Ok(finish_entry_node(
+        entry, children, bare, root, depth, opts, state,
+    ))
+}
+
+/*
+Make a node of an entry and its built children. A directory left with
+nothing to show is dropped, unless --mark-empty keeps it for being bare,
+which is asked only then.
+*/
+fn finish_entry_node(
+    entry: EntryMeta,
+    children: Option<Vec<TreeNode>>,
+    bare: impl FnOnce(&Path) -> bool,
+    root: &Path,
+    depth: usize,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Option<TreeNode> {
+    let at_depth_limit = opts.max_depth.is_some_and(|max| depth >= max);
+    let no_children = matches!(children, Some(ref v) if v.is_empty());
+    // filtered-out contents still count, so only a bare directory is "empty"
+    let empty_dir =
+        entry.is_dir && !at_depth_limit && no_children && opts.mark_empty && bare(&entry.path);
+    if entry.is_dir && !at_depth_limit && no_children && !empty_dir {
+        return None;
+    }
+
+    let duplicate_link =
+        opts.dedup_links && entry.link_id.is_some_and(|id| !state.seen_links.insert(id));
+    state.deepest = state.deepest.max(depth);
+
+    Some(TreeNode {
+        json_path: json_path_for(&entry.path, root, &opts.json_paths),
+        name_bytes: raw_name_bytes(&entry.path, opts),
+        extension: extension_of(&entry.path, entry.is_dir),
+        name: entry.name,
+        path: entry.path,
+        size: entry.size,
+        mtime: entry.mtime,
+        inode: entry.inode,
+        is_dir: entry.is_dir,
+        kind: entry.kind,
+        duplicate_link,
+        context: entry.context,
+        empty_dir,
+        collapsed: 0,
+        file_count: None,
+        total_size: None,
+        children,
+    })
+}
+
+/*
+Archives that can be listed in place of a directory. The format is picked
+by extension, or by magic bytes for files without a telling name.
+*/
+#[derive(Debug, Clone, Copy)]
+enum ArchiveFormat {
+    Zip,
+    Tar { gzip: bool },
+}
+
+impl ArchiveFormat {
+    fn detect(path: &Path, md: &fs::Metadata) -> Option<Self> {
+        if !md.is_file() {
+            return None;
+        }
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            return Some(ArchiveFormat::Zip);
+        }
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            return Some(ArchiveFormat::Tar { gzip: true });
+        }
+        if name.ends_with(".tar") {
+            return Some(ArchiveFormat::Tar { gzip: false });
+        }
+
+        let file = fs::File::open(path).ok()?;
+        match read_magic(file) {
+            [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Some(ArchiveFormat::Zip),
+            [0x1f, 0x8b, ..] => {
+                let file = fs::File::open(path).ok()?;
+                let inner = read_magic(flate2::read::GzDecoder::new(file));
+                (&inner[257..262] == b"ustar").then_some(ArchiveFormat::Tar { gzip: true })
+            }
+            magic if &magic[257..262] == b"ustar" => Some(ArchiveFormat::Tar { gzip: false }),
+            _ => None,
+        }
+    }
+}
+
+// enough of the start of a file to reach the ustar magic at offset 257
+fn read_magic(mut reader: impl Read) -> [u8; 262] {
+    let mut magic = [0; 262];
+    let mut read = 0;
+    while read < magic.len() {
+        match reader.read(&mut magic[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n,
+        }
+    }
+    magic
+}
+
+/*
+One member of an archive, with its path as stored ('/'-separated). Lines
+are only counted when --min-lines needs them.
+*/
+struct ArchiveEntry {
+    path: String,
+    size: u64,
+    mtime: SystemTime,
+    kind: EntryKind,
+    lines: Option<usize>,
+}
+
+/*
+An archive member, or a directory the archive only implies (members stored
+without their parent entries), with what it holds in archive order
+*/
+struct ArchiveMember {
+    size: u64,
+    mtime: SystemTime,
+    kind: EntryKind,
+    lines: Option<usize>,
+    children: Vec<(String, ArchiveMember)>,
+}
+
+impl ArchiveMember {
+    // an implied directory until an entry of its own says otherwise
+    fn implied_dir() -> Self {
+        ArchiveMember {
+            size: 0,
+            mtime: SystemTime::UNIX_EPOCH,
+            kind: EntryKind::Dir,
+            lines: None,
+            children: Vec::new(),
+        }
+    }
+}
+
+/*
+List an archive as the root's children. Members go through the same
+filters, ordering and --max-depth cut-off as the entries of a real walk.
+*/
+fn archive_children(
+    root_path: &Path,
+    format: ArchiveFormat,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Result<Vec<TreeNode>, ParseError> {
+    let count_lines = opts.min_lines.is_some();
+    let entries = match format {
+        ArchiveFormat::Zip => read_zip_entries(root_path, count_lines),
+        ArchiveFormat::Tar { gzip } => read_tar_entries(root_path, gzip, count_lines),
+    }
+    .map_err(|e| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!("reading archive {}: {e}", root_path.display())),
+        })
+    })?;
+
+    let mut members = Vec::new();
+    for entry in entries {
+        insert_archive_entry(&mut members, entry);
+    }
+    Ok(archive_level(members, root_path, root_path, 1, opts, state))
+}
+
+fn insert_archive_entry(members: &mut Vec<(String, ArchiveMember)>, entry: ArchiveEntry) {
+    let parts: Vec<&str> = entry
+        .path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    let Some((leaf, parents)) = parts.split_last() else {
+        return;
+    };
+
+    let mut level = members;
+    for part in parents {
+        let idx = match level.iter().position(|(name, _)| name == part) {
+            Some(idx) => idx,
+            None => {
+                level.push((part.to_string(), ArchiveMember::implied_dir()));
+                level.len() - 1
+            }
+        };
+        level[idx].1.kind = EntryKind::Dir;
+        level = &mut level[idx].1.children;
+    }
+
+    let idx = match level.iter().position(|(name, _)| name == leaf) {
+        Some(idx) => idx,
+        None => {
+            level.push((leaf.to_string(), ArchiveMember::implied_dir()));
+            level.len() - 1
+        }
+    };
+    let member = &mut level[idx].1;
+    member.size = entry.size;
+    member.mtime = entry.mtime;
+    member.kind = entry.kind;
+    member.lines = entry.lines;
+    if member.kind != EntryKind::Dir {
+        member.children.clear();
+    }
+}
+
+/*
+Filter and order the members of one archive directory as the walk does a
+real one, then build the subtrees of those that are kept
+*/
+fn archive_level(
+    members: Vec<(String, ArchiveMember)>,
+    dir: &Path,
+    root_path: &Path,
+    depth: usize,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+) -> Vec<TreeNode> {
+    let mut candidates = Vec::with_capacity(members.len());
+    let mut contents = HashMap::new();
+    for (name, member) in members {
+        let path = dir.join(&name);
+        let is_dir = member.kind == EntryKind::Dir;
+        let Some(context) = name_filter(&name, &path, is_dir, opts, state) else {
+            continue;
+        };
+        let lines = || member.lines;
+        if !metadata_filter(
+            is_dir,
+            member.kind,
+            member.size,
+            lines,
+            opts,
+            &mut state.filtered,
+        ) {
+            continue;
+        }
+        candidates.push(EntryMeta {
+            name: name.clone(),
+            path,
+            size: member.size,
+            mtime: member.mtime,
+            inode: None,
+            link_id: None,
+            context,
+            is_dir,
+            kind: member.kind,
+        });
+        contents.insert(name, member.children);
+    }
+
+    let mut nodes = Vec::with_capacity(candidates.len());
+    for entry in order_meta_entries(candidates, opts, state) {
+        let members = contents.remove(&entry.name).unwrap_or_default();
+        let bare = members.is_empty();
+        let children = if !entry.is_dir {
+            None
+        } else if opts.max_depth.is_some_and(|max| depth >= max) {
+            Some(Vec::new())
+        } else {
+            Some(archive_level(
+                members,
+                &entry.path,
+                root_path,
+                depth + 1,
+                opts,
+                state,
+            ))
+        };
+        if let Some(node) =
+            finish_entry_node(entry, children, |_| bare, root_path, depth, opts, state)
+        {
+            nodes.push(node);
+        }
+    }
+    order_built_nodes(&mut nodes, opts);
+    nodes
+}
+
+/*
+List a zip file from its central directory, zip64 included. Member data is
+only read, and inflated, to count lines for --min-lines.
+*/
+fn read_zip_entries(path: &Path, count_lines: bool) -> io::Result<Vec<ArchiveEntry>> {
+    let file = io::BufReader::new(fs::File::open(path)?);
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    let mut entries = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let mut entry = {
+            let member = archive.by_index_data(i)?;
+            let kind = if member.is_dir() {
+                EntryKind::Dir
+            } else if member.is_symlink() {
+                EntryKind::Symlink
+            } else {
+                EntryKind::File
+            };
+            ArchiveEntry {
+                path: member
+                    .name()
+                    .map(Cow::into_owned)
+                    .unwrap_or_else(|_| String::from_utf8_lossy(member.name_raw()).into_owned()),
+                size: member.size(),
+                mtime: member
+                    .last_modified()
+                    .map_or(SystemTime::UNIX_EPOCH, zip_time),
+                kind,
+                lines: None,
+            }
+        };
+        if count_lines && entry.kind == EntryKind::File {
+            entry.lines = archive.by_index(i).ok().and_then(count_lines_in);
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/*
+Zip stores local wall-clock time to two-second precision
+*/
+fn zip_time(stamp: zip::DateTime) -> SystemTime {
+    use chrono::TimeZone;
+
+    chrono::NaiveDate::from_ymd_opt(
+        i32::from(stamp.year()),
+        u32::from(stamp.month()),
+        u32::from(stamp.day()),
+    )
+    .and_then(|day| {
+        day.and_hms_opt(
+            u32::from(stamp.hour()),
+            u32::from(stamp.minute()),
+            u32::from(stamp.second()),
+        )
     })
+    .and_then(|local| Local.from_local_datetime(&local).earliest())
+    .map(SystemTime::from)
+    .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/*
+List a tar file, gzipped or not, header by header. Member data is skipped
+over unless --min-lines needs its lines counted.
+*/
+fn read_tar_entries(path: &Path, gzip: bool, count_lines: bool) -> io::Result<Vec<ArchiveEntry>> {
+    let file = io::BufReader::new(fs::File::open(path)?);
+    let reader: Box<dyn Read> = if gzip {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for member in archive.entries()? {
+        let mut member = member?;
+        let header_type = member.header().entry_type();
+        // global pax headers describe no member
+        if header_type.is_pax_global_extensions() {
+            continue;
+        }
+
+        let path = String::from_utf8_lossy(&member.path_bytes()).into_owned();
+        let kind = if header_type.is_dir() || path.ends_with('/') {
+            EntryKind::Dir
+        } else if header_type.is_symlink() {
+            EntryKind::Symlink
+        } else if header_type.is_character_special() {
+            EntryKind::CharDevice
+        } else if header_type.is_block_special() {
+            EntryKind::BlockDevice
+        } else if header_type.is_fifo() {
+            EntryKind::Fifo
+        } else {
+            EntryKind::File
+        };
+        let mut mtime = member.header().mtime().unwrap_or(0) as f64;
+        if let Some(pax) = member.pax_extensions()? {
+            for record in pax.flatten() {
+                if record.key() == Ok("mtime") {
+                    mtime = record
+                        .value()
+                        .ok()
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(mtime);
+                }
+            }
+        }
+        let size = member.size();
+        let lines = if count_lines && kind == EntryKind::File {
+            count_lines_in(&mut member)
+        } else {
+            None
+        };
+
+        entries.push(ArchiveEntry {
+            path,
+            size,
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs_f64(mtime.max(0.0)),
+            kind,
+            lines,
+        });
+    }
+    Ok(entries)
+}
+
+/*
+Lazily walk the tree below path, yielding entries in the same pre-order the
+printed tree uses. Directories are yielded before their contents and, unlike
+the built tree, are not dropped when filters leave them empty.
+*/
+pub fn walk<'a>(
+    path: &Path,
+    opts: &'a ScanOptions,
+) -> impl Iterator<Item = Result<EntryMeta, ParseError>> + 'a {
+    Walk {
+        opts,
+        state: WalkState::new(opts),
+        levels: Vec::new(),
+        descend: Some(path.to_path_buf()),
+    }
+}
+
+struct Walk<'a> {
+    opts: &'a ScanOptions,
+    state: WalkState,
+    levels: Vec<std::vec::IntoIter<EntryMeta>>,
+    descend: Option<PathBuf>,
+}
+
+impl Walk<'_> {
+    fn expand_pending(&mut self) -> Result<(), ParseError> {
+        if let Some(dir) = self.descend.take() {
+            let entries = create_ordered_row_level_entries(&dir, self.opts, &mut self.state)?;
+            self.levels.push(entries.into_iter());
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<EntryMeta, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Err(e) = self.expand_pending() {
+                return Some(Err(e));
+            }
+
+            let depth = self.levels.len();
+            let Some(entry) = self.levels.last_mut()?.next() else {
+                self.levels.pop();
+                continue;
+            };
+
+            let at_depth_limit = self.opts.max_depth.is_some_and(|max| depth >= max);
+            if entry.is_dir && !at_depth_limit {
+                self.descend = Some(entry.path.clone());
+            }
+            if depth >= self.opts.min_depth {
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+/*
+Call visitor with each entry and its depth (1 for the root's children) as
+it is discovered. Returning Visit::SkipSubtree from a directory stops the
+walk from descending into it.
+*/
+pub fn scan_with<F>(path: &Path, opts: &ScanOptions, mut visitor: F) -> Result<(), ParseError>
+where
+    F: FnMut(&EntryMeta, usize) -> Visit,
+{
+    let mut state = WalkState::new(opts);
+    scan_level(path, 1, opts, &mut state, &mut visitor)
+}
+
+fn scan_level<F>(
+    dir: &Path,
+    depth: usize,
+    opts: &ScanOptions,
+    state: &mut WalkState,
+    visitor: &mut F,
+) -> Result<(), ParseError>
+where
+    F: FnMut(&EntryMeta, usize) -> Visit,
+{
+    for entry in create_ordered_row_level_entries(dir, opts, state)? {
+        let visit = if depth >= opts.min_depth {
+            visitor(&entry, depth)
+        } else {
+            Visit::Continue
+        };
+
+        let at_depth_limit = opts.max_depth.is_some_and(|max| depth >= max);
+        if entry.is_dir && !at_depth_limit && visit == Visit::Continue {
+            scan_level(&entry.path, depth + 1, opts, state, visitor)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+--newest/--oldest: keep only the N files with the latest or earliest mtime
+anywhere in the tree
+*/
+#[derive(Debug, Clone, Copy)]
+enum Recency {
+    Newest(usize),
+    Oldest(usize),
+}
+
+fn pick_by_mtime(root: &TreeNode, recency: Recency) -> HashSet<PathBuf> {
+    fn visit<'a>(
+        node: &'a TreeNode,
+        newest: bool,
+        limit: usize,
+        heap: &mut BinaryHeap<(i128, &'a Path)>,
+    ) {
+        for child in node.children.iter().flatten() {
+            if child.is_dir {
+                visit(child, newest, limit, heap);
+                continue;
+            }
+            if child.context {
+                continue;
+            }
+            let nanos = match child.mtime.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => d.as_nanos() as i128,
+                Err(e) => -(e.duration().as_nanos() as i128),
+            };
+            // the heap's top is the worst candidate kept so far
+            heap.push((if newest { -nanos } else { nanos }, &child.path));
+            if heap.len() > limit {
+                heap.pop();
+            }
+        }
+    }
+
+    let (newest, limit) = match recency {
+        Recency::Newest(n) => (true, n),
+        Recency::Oldest(n) => (false, n),
+    };
+    let mut heap = BinaryHeap::with_capacity(limit + 1);
+    visit(root, newest, limit, &mut heap);
+    heap.into_iter()
+        .map(|(_, path)| path.to_path_buf())
+        .collect()
+}
+
+/*
+Drop every file not in keep, then any directory left without entries.
+Returns whether anything under node survived.
+*/
+fn retain_files(node: &mut TreeNode, keep: &HashSet<PathBuf>, filtered: &mut FilterStats) -> bool {
+    let Some(children) = node.children.as_mut() else {
+        return keep.contains(&node.path);
+    };
+    children.retain_mut(|child| {
+        let kept = retain_files(child, keep, filtered);
+        // context entries were already counted by the filter that dimmed them
+        if !kept && !child.is_dir && !child.context {
+            filtered.not_recent += 1;
+        }
+        kept
+    });
+    !children.is_empty()
+}
+
+/*
+Keep the entries matching the --glob-root pattern, everything below a
+matching directory, and the directories leading to a match
+*/
+fn retain_glob_matches(node: &mut TreeNode, base: &Path, glob: &GlobPattern) -> bool {
+    if let Ok(rel) = node.path.strip_prefix(base) {
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        if !rel.is_empty() && glob.matches_relative(&rel) {
+            return true;
+        }
+    }
+    let Some(children) = node.children.as_mut() else {
+        return false;
+    };
+    children.retain_mut(|child| retain_glob_matches(child, base, glob));
+    !children.is_empty()
+}
+
+/*
+Store each directory's recursive file count and size on the node, counting
+the same way as the summary line. Returns the totals for node's subtree.
+*/
+fn fill_subtree_stats(node: &mut TreeNode) -> Stats {
+    let mut totals = Stats::default();
+    for child in node.children.iter_mut().flatten() {
+        accumulate(&mut totals, child);
+        let below = fill_subtree_stats(child);
+        totals.files += below.files;
+        totals.size += below.size;
+    }
+    if node.is_dir {
+        node.file_count = Some(totals.files);
+        node.total_size = Some(totals.size);
+    }
+    totals
+}
+
+/*
+Replace nodes shallower than min_depth with their children, labelling each
+hoisted node with the path segments that were skipped to reach it
+*/
+fn hoist_above_min_depth(nodes: Vec<TreeNode>, depth: usize, min_depth: usize) -> Vec<TreeNode> {
+    if depth >= min_depth {
+        return nodes;
+    }
+
+    let mut hoisted = Vec::new();
+    for mut node in nodes {
+        let Some(children) = node.children.take() else {
+            continue;
+        };
+        for mut child in hoist_above_min_depth(children, depth + 1, min_depth) {
+            child.name = format!("{}/{}", node.name, child.name);
+            hoisted.push(child);
+        }
+    }
+    hoisted
+}
+
+/*
+Fold each directory whose only child is a directory into that child, so
+a/b/c renders on a single line. Any file in a directory stops the chain.
+The merged node keeps a count of the directories it absorbed so the
+summary totals match the uncollapsed tree.
+*/
+fn collapse_single_child_dirs(nodes: &mut [TreeNode]) {
+    for node in nodes.iter_mut() {
+        while let Some(only) = take_only_subdir(node) {
+            node.name = format!("{}/{}", node.name, only.name);
+            node.path = only.path;
+            node.json_path = only.json_path;
+            node.size = only.size;
+            node.mtime = only.mtime;
+            node.inode = only.inode;
+            node.kind = only.kind;
+            node.collapsed += 1 + only.collapsed;
+            node.children = only.children;
+        }
+        if let Some(children) = node.children.as_mut() {
+            collapse_single_child_dirs(children);
+        }
+    }
+}
+
+fn take_only_subdir(node: &mut TreeNode) -> Option<TreeNode> {
+    match node.children.as_mut() {
+        Some(kids) if kids.len() == 1 && kids[0].is_dir => kids.pop(),
+        _ => None,
+    }
+}
+
+/*
+Return the path recorded for a node in JSON output, if any
+*/
+fn json_path_for(path: &Path, root: &Path, mode: &JsonPathMode) -> Option<PathBuf> {
+    match mode {
+        JsonPathMode::Scanned => Some(path.to_path_buf()),
+        JsonPathMode::Relative => {
+            let rel = path.strip_prefix(root).unwrap_or(path);
+            if rel.as_os_str().is_empty() {
+                return Some(PathBuf::from("."));
+            }
+            // always '/' so exports from Windows and Unix hosts compare equal
+            let parts: Vec<_> = rel
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            Some(PathBuf::from(parts.join("/")))
+        }
+        JsonPathMode::Omitted => None,
+    }
+}
+
+/*
+Tree glyphs for one indentation width. Each prefix piece is exactly that
+many columns wide so nested prefixes line up; the outline style drops the
+connectors and indents with spaces alone. The kind prefixes go between the
+connector and the name and are left alone when the glyphs are restyled.
+*/
+#[derive(Debug, Clone, Default)]
+struct Connectors {
+    branch: String,
+    last: String,
+    // the last connector mirrored, for the first child printed bottom-up
+    top: String,
+    pipe: String,
+    blank: String,
+    dir_prefix: String,
+    file_prefix: String,
+}
+
+impl Connectors {
+    fn new(width: usize, outline: bool) -> Self {
+        let mut connectors = Connectors::default();
+        connectors.restyle(width, outline);
+        connectors
+    }
+
+    fn restyle(&mut self, width: usize, outline: bool) {
+        self.blank = " ".repeat(width);
+        if outline {
+            self.branch.clear();
+            self.last.clear();
+            self.top.clear();
+            self.pipe = " ".repeat(width);
+            return;
+        }
+
+        let rule = "─".repeat(width.saturating_sub(2));
+        self.branch = format!("├{rule} ");
+        self.last = format!("└{rule} ");
+        self.top = format!("┌{rule} ");
+        self.pipe = format!("│{}", " ".repeat(width.saturating_sub(1)));
+    }
+
+    fn kind_prefix(&self, is_dir: bool) -> &str {
+        if is_dir {
+            &self.dir_prefix
+        } else {
+            &self.file_prefix
+        }
+    }
+
+    fn pick(&self, is_last: bool, bottom_up: bool) -> (&str, &str) {
+        if is_last && bottom_up {
+            (&self.top, &self.blank)
+        } else if is_last {
+            (&self.last, &self.blank)
+        } else {
+            (&self.branch, &self.pipe)
+        }
+    }
+}
+
+/*
+State carried through one render: the running totals for the summary line
+and the column widths measured before the first row is printed
+*/
+#[derive(Default)]
+struct PrintState {
+    stats: Stats,
+    // the part of stats found directly under the root, for --breakdown
+    top_level: Stats,
+    // file counts indexed like FileCategory::ALL
+    categories: [usize; FileCategory::ALL.len()],
+    // nesting level of the node being printed, 1 for the root's children
+    depth: usize,
+    size_width: usize,
+    inode_width: usize,
+    // --fold-identical: structural hash of every non-empty directory, and the
+    // first directory printed with each hash
+    shapes: HashMap<PathBuf, u64>,
+    first_shape: HashMap<u64, PathBuf>,
+}
+
+impl PrintState {
+    // first pass of the aligned layout: find the widest value in each column
+    fn measure(root: &TreeNode, opts: &ScanOptions) -> Self {
+        let mut state = PrintState::default();
+        if opts.long_format && opts.align {
+            state.size_width = format_size(root.size).len();
+            state.inode_width = inode_column(root).len();
+            state.measure_children(root, opts);
+        }
+        if opts.fold_identical {
+            state.fingerprint(root, opts);
+        }
+        state
+    }
+
+    /*
+    Hash the names and kinds of everything below a directory, so two
+    directories with the same contents get the same value whatever they are
+    called themselves
+    */
+    fn fingerprint(&mut self, node: &TreeNode, opts: &ScanOptions) -> u64 {
+        let children = visible_children(node, opts);
+        let mut hasher = DefaultHasher::new();
+        for child in &children {
+            child.name.hash(&mut hasher);
+            child.is_dir.hash(&mut hasher);
+            if child.is_dir {
+                self.fingerprint(child, opts).hash(&mut hasher);
+            }
+        }
+        let shape = hasher.finish();
+        if !children.is_empty() {
+            self.shapes.insert(node.path.clone(), shape);
+        }
+        shape
+    }
+
+    // the earlier directory this one repeats, if any
+    fn folded_into(&self, node: &TreeNode) -> Option<&Path> {
+        let shape = self.shapes.get(&node.path)?;
+        self.first_shape
+            .get(shape)
+            .filter(|first| **first != node.path)
+            .map(PathBuf::as_path)
+    }
+
+    fn measure_children(&mut self, node: &TreeNode, opts: &ScanOptions) {
+        for child in visible_children(node, opts) {
+            self.size_width = self.size_width.max(format_size(child.size).len());
+            self.inode_width = self.inode_width.max(inode_column(child).len());
+            self.measure_children(child, opts);
+        }
+    }
+}
+
+/*
+Print the directory tree to standard out or write to JSON
+*/
+fn print_tree(
+    node: &TreeNode,
+    connector: &str,
+    prefix: &mut String,
+    state: &mut PrintState,
+    opts: &ScanOptions,
+    w: &mut dyn FnMut(&str),
+) {
+    // counted before rendering so --running-total includes this file
+    accumulate(&mut state.stats, node);
+    if state.depth == 1 {
+        accumulate(&mut state.top_level, node);
+        // what --collapse folded into it lies below the top level
+        state.top_level.dirs -= node.collapsed;
+    }
+    if !opts.bottom_up {
+        render_node(node, connector, prefix, state, opts, w);
+    }
+    if !node.is_dir && !node.context {
+        state.categories[FileCategory::of(&node.path) as usize] += 1;
+    }
+    if let Some(&shape) = state.shapes.get(&node.path) {
+        state
+            .first_shape
+            .entry(shape)
+            .or_insert_with(|| node.path.clone());
+    }
+    if state.folded_into(node).is_some() {
+        // still walked so the summary counts the hidden entries, but silently
+        for child in visible_children(node, opts) {
+            state.depth += 1;
+            print_tree(child, "", prefix, state, opts, &mut |_| {});
+            state.depth -= 1;
+        }
+        if opts.bottom_up {
+            render_node(node, connector, prefix, state, opts, w);
+        }
+        return;
+    }
+
+    let children = visible_children(node, opts);
+    let last = children.len().saturating_sub(1);
+    for (i, child) in sibling_order(&children, opts) {
+        let (conn, indent) = opts.connectors.pick(i == last, opts.bottom_up);
+        let len = prefix.len();
+        prefix.push_str(indent);
+        state.depth += 1;
+        print_tree(child, conn, prefix, state, opts, w);
+        state.depth -= 1;
+        prefix.truncate(len);
+    }
+
+    if opts.bottom_up {
+        render_node(node, connector, prefix, state, opts, w);
+    }
+}
+
+/*
+Children with their positions, last first when printing bottom-up so the
+output is the top-down tree mirrored
+*/
+fn sibling_order<'a>(children: &[&'a TreeNode], opts: &ScanOptions) -> Vec<(usize, &'a TreeNode)> {
+    let mut order: Vec<_> = children.iter().copied().enumerate().collect();
+    if opts.bottom_up {
+        order.reverse();
+    }
+    order
+}
+
+fn render_node(
+    node: &TreeNode,
+    connector: &str,
+    prefix: &str,
+    state: &PrintState,
+    opts: &ScanOptions,
+    w: &mut dyn FnMut(&str),
+) {
+    let style = if opts
+        .highlight
+        .as_ref()
+        .is_some_and(|re| re.is_match(&node.name))
+    {
+        NameStyle::Highlighted
+    } else if node.context {
+        NameStyle::Dimmed
+    } else if let Some(ref buckets) = opts.heat {
+        NameStyle::Tinted(heat_color(node.mtime, buckets))
+    } else if opts.size_heat && node.is_dir {
+        let mut subtree = Stats::default();
+        accumulate_subtree(&mut subtree, node);
+        NameStyle::Sized(subtree.size)
+    } else if opts.size_heat {
+        NameStyle::Sized(node.size)
+    } else if opts.depth_colors {
+        NameStyle::Tinted(DEPTH_PALETTE[(state.depth.max(1) - 1) % DEPTH_PALETTE.len()])
+    } else {
+        NameStyle::Kind
+    };
+    let shown = truncate_name(&node.name, opts.max_name_length);
+    let (stats, mut name) = entry_lines(node, &shown, style, opts);
+    if opts.outline && node.is_dir {
+        name.push('/');
+    }
+    if opts.count_badge && node.is_dir {
+        let mut subtree = Stats::default();
+        accumulate_subtree(&mut subtree, node);
+        name = format!("{name} ({} files)", subtree.files);
+    }
+    if opts.size_inline && !node.context {
+        let bytes = if node.is_dir {
+            let mut subtree = Stats::default();
+            accumulate_subtree(&mut subtree, node);
+            subtree.size
+        } else {
+            node.size
+        };
+        name = format!("{name} ({})", format_size(bytes).trim_end());
+    }
+    if opts.child_count && node.is_dir {
+        name = format!("{name} [{}]", direct_children(node));
+    }
+    if opts.running_total && !node.is_dir && !node.context {
+        name = format!(
+            "{name} (total {})",
+            format_size(state.stats.size).trim_end()
+        );
+    }
+    if let Some(first) = state.folded_into(node) {
+        name = format!("{name} <same as {}>", shown_path(first, opts));
+    }
+    if node.duplicate_link {
+        name = format!("{name} [hard link]");
+    }
+    if node.empty_dir {
+        name = format!("{name} (empty)");
+    }
+
+    let mut name = format!("{}{name}", opts.connectors.kind_prefix(node.is_dir));
+    if opts.show_perms {
+        name = format!("[{}] {name}", perms_column(&node.path));
+    }
+
+    emit_rows(node, (&stats, &name), connector, prefix, state, opts, w);
+}
+
+/*
+Write the line(s) for one node whose label is already formatted: a single
+aligned row, or the name line followed by the long-format stats line
+*/
+fn emit_rows(
+    node: &TreeNode,
+    (stats, name): (&str, &str),
+    connector: &str,
+    prefix: &str,
+    state: &PrintState,
+    opts: &ScanOptions,
+    w: &mut dyn FnMut(&str),
+) {
+    if opts.long_format && opts.align {
+        let mut row = format!(
+            "{:>width$}  {}  ",
+            format_size(node.size),
+            format_time(node.mtime),
+            width = state.size_width
+        );
+        if opts.show_inode {
+            row.push_str(&format!(
+                "{:>width$}  ",
+                inode_column(node),
+                width = state.inode_width
+            ));
+        }
+        w(&format!("{row}{prefix}{connector}{name}"));
+        return;
+    }
+
+    w(&format!("{prefix}{connector}{name}"));
+    // the stats line sits one indentation level in, under the name
+    let pad = " ".repeat(opts.indent);
+    if opts.long_format && opts.show_inode {
+        let inode = inode_column(node);
+        w(&format!("{prefix}{pad}{stats} {:<10} {inode}", "Inode:"));
+    } else if opts.long_format {
+        w(&format!("{prefix}{pad}{stats}"));
+    }
+}
+
+/*
+ls-style type and permission bits, e.g. drwxr-xr-x
+*/
+#[cfg(unix)]
+fn perms_column(path: &Path) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Ok(md) = fs::symlink_metadata(path) else {
+        return "?".repeat(10);
+    };
+    let mut perms = String::with_capacity(10);
+    perms.push(match EntryKind::from_file_type(&md.file_type()) {
+        EntryKind::Dir => 'd',
+        EntryKind::Symlink => 'l',
+        EntryKind::Fifo => 'p',
+        EntryKind::Socket => 's',
+        EntryKind::BlockDevice => 'b',
+        EntryKind::CharDevice => 'c',
+        EntryKind::File => '-',
+    });
+    let mode = md.permissions().mode();
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        perms.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    perms
+}
+
+#[cfg(not(unix))]
+fn perms_column(path: &Path) -> String {
+    match fs::symlink_metadata(path) {
+        Ok(md) if md.permissions().readonly() => "r--".into(),
+        Ok(_) => "rw-".into(),
+        Err(_) => "???".into(),
+    }
+}
+
+// --depth-colors cycles through these, starting at the root's children
+const DEPTH_PALETTE: [Color; 6] = [
+    Color::BrightBlue,
+    Color::BrightGreen,
+    Color::BrightYellow,
+    Color::BrightMagenta,
+    Color::BrightCyan,
+    Color::BrightRed,
+];
+
+/*
+Pick a color by which age bucket mtime falls in, stepping from green for the
+newest bucket to red for anything older than the last threshold
+*/
+fn heat_color(mtime: SystemTime, buckets: &[Duration]) -> Color {
+    let age = SystemTime::now()
+        .duration_since(mtime)
+        .unwrap_or(Duration::ZERO);
+    let bucket = buckets.iter().take_while(|&&limit| age > limit).count();
+    let step = bucket as f64 / buckets.len().max(1) as f64;
+    let red = (220.0 * step) as u8;
+    let green = (200.0 * (1.0 - step)) as u8;
+    Color::TrueColor {
+        r: red,
+        g: green,
+        b: 0,
+    }
+}
+
+/*
+Cut a name to at most max characters, the last one being an ellipsis
+*/
+fn truncate_name(name: &str, max: Option<usize>) -> Cow<'_, str> {
+    match max {
+        Some(max) if name.chars().count() > max => {
+            let mut short: String = name.chars().take(max.saturating_sub(1)).collect();
+            short.push('…');
+            Cow::Owned(short)
+        }
+        _ => Cow::Borrowed(name),
+    }
+}
+
+fn inode_column(node: &TreeNode) -> String {
+    node.inode
+        .map(|ino| ino.to_string())
+        .unwrap_or_else(|| "-".into())
+}
+
+fn visible_children<'a>(node: &'a TreeNode, opts: &ScanOptions) -> Vec<&'a TreeNode> {
+    node.children
+        .iter()
+        .flatten()
+        .filter(|child| !opts.dirs_only || child.is_dir)
+        .take(opts.sample.unwrap_or(usize::MAX))
+        .collect()
+}
+
+/*
+Files under the root whose extension is `ext`, honouring the same case rule
+as --extension; context entries are not counted
+*/
+fn count_extension(root: &TreeNode, ext: &str, opts: &ScanOptions) -> usize {
+    visible_children(root, opts)
+        .into_iter()
+        .map(|child| {
+            if child.is_dir {
+                return count_extension(child, ext, opts);
+            }
+            let own = child.path.extension().and_then(|e| e.to_str());
+            let hit = !child.context
+                && own.is_some_and(|own| {
+                    if opts.case_sensitive_ext {
+                        own == ext
+                    } else {
+                        own.eq_ignore_ascii_case(ext)
+                    }
+                });
+            usize::from(hit)
+        })
+        .sum()
+}
+
+/*
+Every file kept in the tree, as a sorted set. Sorting here rather than
+relying on the walk keeps the order stable across --sort and --pin.
+*/
+fn file_paths<'a>(root: &'a TreeNode, opts: &ScanOptions) -> BTreeSet<&'a Path> {
+    fn visit<'a>(node: &'a TreeNode, opts: &ScanOptions, paths: &mut BTreeSet<&'a Path>) {
+        for child in visible_children(node, opts) {
+            if child.is_dir {
+                visit(child, opts, paths);
+            } else if !child.context {
+                paths.insert(&child.path);
+            }
+        }
+    }
+
+    let mut paths = BTreeSet::new();
+    visit(root, opts, &mut paths);
+    paths
+}
+
+/*
+A --json snapshot as read by --compare: the nested tree, either bare or
+under the tree key written by --annotate
+*/
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Snapshot {
+    Nodes(Vec<TreeNode>),
+    Annotated {
+        meta: SnapshotMeta,
+        tree: Vec<TreeNode>,
+    },
+}
+
+// the part of the --annotate meta object that --compare reads
+#[derive(Deserialize)]
+struct SnapshotMeta {
+    json_children_only: Option<bool>,
+}
+
+/*
+Whether a snapshot without a recorded shape holds the whole tree rather than
+the root's children: a single directory named like the scanned root, or named
+like no directory entry can be ("." or "/", a root given without a file name)
+*/
+fn holds_whole_tree(nodes: &[TreeNode], live: &TreeNode) -> bool {
+    let [root] = nodes else {
+        return false;
+    };
+    let canonical = fs::canonicalize(&live.path).ok();
+    let live_name = canonical.as_deref().and_then(Path::file_name);
+    root.is_dir
+        && (root.name == live.name
+            || live_name.is_some_and(|name| *name == *root.name)
+            || Path::new(&root.name).file_name() != Some(OsStr::new(&root.name)))
+}
+
+/*
+Diff the tree against a --json snapshot by path relative to the root,
+one line per difference: "+" added, "-" removed, "~" changed. Files change
+when their size or mtime does, directories only when they stop being one.
+*/
+fn compare_snapshot(tree: &TreeNode, file: &Path) -> Result<Vec<String>, ParseError> {
+    fn index<'a>(nodes: &'a [TreeNode], prefix: &str, out: &mut BTreeMap<String, &'a TreeNode>) {
+        for node in nodes.iter().filter(|node| !node.context) {
+            let rel = if prefix.is_empty() {
+                node.name.clone()
+            } else {
+                format!("{prefix}/{}", node.name)
+            };
+            index(node.children.as_deref().unwrap_or_default(), &rel, out);
+            out.insert(rel, node);
+        }
+    }
+
+    let invalid = |why: String| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::InvalidInput(format!(
+                "cannot compare against {}: {why}",
+                file.display()
+            )),
+        })
+    };
+    let raw = fs::read(file).map_err(|e| invalid(e.to_string()))?;
+    let (nodes, children_only) = match serde_json::from_slice(&raw) {
+        Ok(Snapshot::Nodes(nodes)) => (nodes, None),
+        Ok(Snapshot::Annotated { meta, tree }) => (tree, meta.json_children_only),
+        Err(_) => return Err(invalid("not a nested --json tree".into())),
+    };
+    let children_only = children_only.unwrap_or_else(|| !holds_whole_tree(&nodes, tree));
+    let before = match &nodes[..] {
+        _ if children_only => &nodes[..],
+        [root] => root.children.as_deref().unwrap_or_default(),
+        _ => return Err(invalid("expected a single root directory".into())),
+    };
+
+    let (mut old, mut new) = (BTreeMap::new(), BTreeMap::new());
+    index(before, "", &mut old);
+    index(tree.children.as_deref().unwrap_or_default(), "", &mut new);
+
+    let mut lines = Vec::new();
+    for (rel, was) in &old {
+        let Some(now) = new.get(rel) else {
+            lines.push(format!("- {rel}"));
+            continue;
+        };
+        let mut changes = Vec::new();
+        if was.is_dir != now.is_dir {
+            changes.push(
+                if was.is_dir {
+                    "was a directory"
+                } else {
+                    "was a file"
+                }
+                .to_string(),
+            );
+        } else if !now.is_dir {
+            if was.size != now.size {
+                changes.push(format!(
+                    "size {} -> {}",
+                    format_size(was.size).trim_end(),
+                    format_size(now.size).trim_end()
+                ));
+            }
+            if was.mtime != now.mtime {
+                changes.push("modified".to_string());
+            }
+        }
+        if !changes.is_empty() {
+            lines.push(format!("~ {rel} ({})", changes.join(", ")));
+        }
+    }
+    lines.extend(
+        new.keys()
+            .filter(|rel| !old.contains_key(*rel))
+            .map(|rel| format!("+ {rel}")),
+    );
+    lines.sort_by(|a, b| a[2..].cmp(&b[2..]));
+    Ok(lines)
+}
+
+/*
+Every directory below the root as a '/'-separated relative path, sorted,
+for --index
+*/
+fn directory_index(root: &TreeNode, opts: &ScanOptions) -> BTreeSet<PathBuf> {
+    fn visit(node: &TreeNode, root: &Path, opts: &ScanOptions, dirs: &mut BTreeSet<PathBuf>) {
+        for child in visible_children(node, opts) {
+            if child.is_dir && !child.context {
+                dirs.extend(json_path_for(&child.path, root, &JsonPathMode::Relative));
+                visit(child, root, opts, dirs);
+            }
+        }
+    }
+
+    let mut dirs = BTreeSet::new();
+    visit(root, &root.path, opts, &mut dirs);
+    dirs
+}
+
+/*
+Symlinks in the tree whose target cannot be reached, with the target as
+written in the link, for --broken-links
+*/
+fn broken_links<'a>(root: &'a TreeNode, opts: &ScanOptions) -> Vec<(&'a Path, PathBuf)> {
+    fn visit<'a>(node: &'a TreeNode, opts: &ScanOptions, links: &mut Vec<(&'a Path, PathBuf)>) {
+        for child in visible_children(node, opts) {
+            if child.kind == EntryKind::Symlink && fs::metadata(&child.path).is_err() {
+                if let Ok(target) = fs::read_link(&child.path) {
+                    links.push((&child.path, target));
+                }
+            }
+            visit(child, opts, links);
+        }
+    }
+
+    let mut links = Vec::new();
+    visit(root, opts, &mut links);
+    links
+}
+
+fn accumulate(stats: &mut Stats, node: &TreeNode) {
+    if node.context {
+        return;
+    }
+    if node.is_dir {
+        stats.dirs += 1 + node.collapsed;
+    } else {
+        stats.files += 1;
+        if !node.duplicate_link {
+            stats.size += node.size;
+        }
+    }
+}
+
+/*
+Totals over everything the tree view would print, without printing it
+*/
+fn tree_stats(root: &TreeNode, opts: &ScanOptions) -> Stats {
+    fn visit(stats: &mut Stats, node: &TreeNode, opts: &ScanOptions) {
+        for child in visible_children(node, opts) {
+            accumulate(stats, child);
+            visit(stats, child, opts);
+        }
+    }
+
+    let mut stats = Stats::default();
+    visit(&mut stats, root, opts);
+    stats
+}
+
+/*
+Rank files by size x depth (the root's children are depth 1), so a medium
+file five levels down can outrank a larger one at the top
+*/
+fn depth_weighted_lines(root: &TreeNode, limit: usize, opts: &ScanOptions) -> Vec<String> {
+    fn visit<'a>(
+        node: &'a TreeNode,
+        depth: u64,
+        opts: &ScanOptions,
+        ranked: &mut Vec<(u64, u64, &'a TreeNode)>,
+    ) {
+        for child in visible_children(node, opts) {
+            if child.is_dir {
+                visit(child, depth + 1, opts, ranked);
+            } else if !child.context && !child.duplicate_link {
+                ranked.push((child.size.saturating_mul(depth), depth, child));
+            }
+        }
+    }
+
+    let mut ranked = Vec::new();
+    visit(root, 1, opts, &mut ranked);
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.2.path.cmp(&b.2.path)));
+    ranked.truncate(limit);
+
+    let mut lines = vec![format!(
+        "{:>10}  {:>10}  {:>5}  path",
+        "weighted", "size", "depth"
+    )];
+    for (score, depth, node) in ranked {
+        let rel =
+            json_path_for(&node.path, &root.path, &JsonPathMode::Relative).unwrap_or_default();
+        lines.push(format!(
+            "{:>10}  {:>10}  {depth:>5}  {}",
+            format_size(score).trim_end(),
+            format_size(node.size).trim_end(),
+            rel.display()
+        ));
+    }
+    lines
+}
+
+/*
+One row per directory directly under the root with the totals of its
+subtree, biggest first, ties by name
+*/
+fn per_top_level_lines(root: &TreeNode, opts: &ScanOptions) -> Vec<String> {
+    let mut rows: Vec<(&TreeNode, Stats)> = visible_children(root, opts)
+        .into_iter()
+        .filter(|child| child.is_dir && !child.context)
+        .map(|child| (child, tree_stats(child, opts)))
+        .collect();
+    rows.sort_by(|a, b| {
+        b.1.size
+            .cmp(&a.1.size)
+            .then_with(|| a.0.name.cmp(&b.0.name))
+    });
+
+    let mut lines = vec![format!("{:>10}  {:>7}  directory", "size", "files")];
+    for (node, stats) in rows {
+        lines.push(format!(
+            "{:>10}  {:>7}  {}/",
+            format_size(stats.size).trim_end(),
+            stats.files,
+            node.name
+        ));
+    }
+    lines
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ChartMetric {
+    Count,
+    Size,
+}
+
+/*
+One bar per extension, largest first, scaled so the largest bar fills the
+terminal ($COLUMNS, else 80 columns)
+*/
+fn extension_chart(root: &TreeNode, metric: ChartMetric, opts: &ScanOptions) -> Vec<String> {
+    fn visit(
+        node: &TreeNode,
+        metric: ChartMetric,
+        opts: &ScanOptions,
+        totals: &mut Vec<(String, u64)>,
+    ) {
+        for child in visible_children(node, opts) {
+            if child.is_dir {
+                visit(child, metric, opts, totals);
+                continue;
+            }
+            if child.context {
+                continue;
+            }
+            let ext = child
+                .path
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy().to_lowercase()))
+                .unwrap_or_else(|| "(none)".into());
+            let amount = match metric {
+                ChartMetric::Count => 1,
+                ChartMetric::Size if child.duplicate_link => 0,
+                ChartMetric::Size => child.size,
+            };
+            match totals.iter_mut().find(|(e, _)| *e == ext) {
+                Some((_, total)) => *total += amount,
+                None => totals.push((ext, amount)),
+            }
+        }
+    }
+
+    let mut totals = Vec::new();
+    visit(root, metric, opts, &mut totals);
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let value = |amount: u64| match metric {
+        ChartMetric::Count => amount.to_string(),
+        ChartMetric::Size => format_size(amount).trim_end().to_string(),
+    };
+    let label_width = totals.iter().map(|(e, _)| e.len()).max().unwrap_or(0);
+    let value_width = totals
+        .iter()
+        .map(|(_, a)| value(*a).len())
+        .max()
+        .unwrap_or(0);
+    let columns = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse::<usize>().ok())
+        .unwrap_or(80);
+    let bar_width = columns
+        .saturating_sub(label_width + value_width + 3)
+        .max(10) as u64;
+    let largest = totals.first().map_or(0, |(_, a)| *a).max(1);
+
+    totals
+        .iter()
+        .map(|(ext, amount)| {
+            let mut len = (amount * bar_width + largest / 2) / largest;
+            if *amount > 0 {
+                len = len.max(1);
+            }
+            format!(
+                "{ext:>label_width$} {} {}",
+                "#".repeat(len as usize),
+                value(*amount)
+            )
+        })
+        .collect()
+}
+
+// the --warn-depth nudge, when the walk went deeper than allowed
+fn depth_warning(deepest: usize, opts: &ScanOptions) -> Option<String> {
+    let limit = opts.warn_depth.filter(|&limit| deepest > limit)?;
+    Some(format!(
+        "mytree: the tree is {deepest} levels deep (more than {limit}); \
+         consider --max-depth {limit} or a filter"
+    ))
+}
+
+/*
+Non-zero categories, largest first, e.g. "code: 42, docs: 5, images: 3"
+*/
+fn category_line(counts: &[usize]) -> String {
+    let mut present: Vec<_> = FileCategory::ALL
+        .iter()
+        .zip(counts)
+        .filter(|(_, &count)| count > 0)
+        .collect();
+    present.sort_by_key(|(_, &count)| std::cmp::Reverse(count));
+    if present.is_empty() {
+        return "no files".into();
+    }
+    present
+        .iter()
+        .map(|(category, count)| format!("{}: {count}", category.label()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// entries directly inside node, not counting --context siblings
+fn direct_children(node: &TreeNode) -> usize {
+    node.children
+        .iter()
+        .flatten()
+        .filter(|c| !c.context)
+        .count()
+}
+
+fn accumulate_subtree(stats: &mut Stats, node: &TreeNode) {
+    for child in node.children.iter().flatten() {
+        accumulate(stats, child);
+        accumulate_subtree(stats, child);
+    }
+}
+
+fn print_ascii_tree(
+    root: &TreeNode,
+    opts: &ScanOptions,
+    root_path: &Path,
+    filtered: &FilterStats,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    // aligned output needs every column width before the first row goes out
+    let mut state = PrintState::measure(root, opts);
+
+    let mut out = io::BufWriter::new(out);
+
+    // print_tree cannot propagate errors through its line sink, so keep the
+    // first one and stop writing after it
+    let mut written = Ok(());
+    // past --max-lines the walk goes on so the summary stays whole
+    let (mut shown, mut dropped) = (0usize, 0usize);
+    let mut push_line = |line: &str| {
+        if opts.max_lines.is_some_and(|max| shown >= max) {
+            dropped += 1;
+            return;
+        }
+        shown += 1;
+        if written.is_ok() {
+            written = writeln!(out, "{line}");
+        }
+    };
+
+    // the root goes through the same metadata columns as every entry
+    let (root_stats, _) = entry_lines(root, &root.name, NameStyle::Kind, opts);
+    let mut header = root_header(root_path, opts);
+    if opts.show_perms {
+        header = format!("[{}] {header}", perms_column(&root.path));
+    }
+    if !opts.bottom_up {
+        emit_rows(
+            root,
+            (&root_stats, &header),
+            "",
+            "",
+            &state,
+            opts,
+            &mut push_line,
+        );
+    }
+
+    // one prefix buffer is grown and truncated around each recursive call
+    let mut prefix = String::with_capacity(64);
+    let children = visible_children(root, opts);
+    let last = children.len().saturating_sub(1);
+    for (idx, child) in sibling_order(&children, opts) {
+        let (connector, indent) = opts.connectors.pick(idx == last, opts.bottom_up);
+        prefix.push_str(indent);
+        state.depth = 1;
+
+        print_tree(
+            child,
+            connector,
+            &mut prefix,
+            &mut state,
+            opts,
+            &mut push_line,
+        );
+        prefix.clear();
+    }
+    if opts.bottom_up {
+        emit_rows(
+            root,
+            (&root_stats, &header),
+            "",
+            "",
+            &state,
+            opts,
+            &mut push_line,
+        );
+    }
+    written?;
+    if dropped > 0 {
+        writeln!(
+            out,
+            "... truncated at {shown} lines ({dropped} more not shown)"
+        )?;
+    }
+
+    writeln!(
+        out,
+        "\n{} directories, {} files, {} bytes total",
+        state.stats.dirs,
+        state.stats.files,
+        format_size(state.stats.size)
+    )?;
+
+    if let Some(node) = opts.summary_for.as_deref().and_then(|p| find_node(root, p)) {
+        let scoped = tree_stats(node, opts);
+        writeln!(
+            out,
+            "{}: {} directories, {} files, {} bytes total",
+            shown_path(&node.path, opts),
+            scoped.dirs,
+            scoped.files,
+            format_size(scoped.size)
+        )?;
+    }
+
+    if opts.disk {
+        match DiskUsage::of(root_path) {
+            Ok(disk) => {
+                // the share of the disk this tree takes, against its whole size
+                let share = state.stats.size as f64 * 100.0 / disk.total.max(1) as f64;
+                writeln!(
+                    out,
+                    "disk: {} used of {} ({} free); the tree is {share:.1}% of it",
+                    format_size(disk.used).trim_end(),
+                    format_size(disk.total).trim_end(),
+                    format_size(disk.free).trim_end()
+                )?;
+            }
+            Err(e) => eprintln!(
+                "mytree: could not read disk usage for {}: {e}",
+                root_path.display()
+            ),
+        }
+    }
+
+    if opts.breakdown {
+        let (top, all) = (&state.top_level, &state.stats);
+        writeln!(
+            out,
+            "top-level: {} directories, {} files; nested: {} directories, {} files",
+            top.dirs,
+            top.files,
+            all.dirs - top.dirs,
+            all.files - top.files
+        )?;
+    }
+
+    if opts.category_summary {
+        writeln!(out, "{}", category_line(&state.categories))?;
+    }
+
+    if opts.show_filtered_count {
+        writeln!(out, "({} entries hidden by filters)", filtered.total())?;
+    }
+
+    if opts.legend {
+        for line in legend_lines(opts) {
+            writeln!(out, "{line}")?;
+        }
+    }
+
+    out.flush()
+}
+
+/*
+Space on the filesystem holding a path, in bytes, counted the way POSIX df
+does: free is what an unprivileged user may still use
+*/
+struct DiskUsage {
+    total: u64,
+    used: u64,
+    free: u64,
+}
+
+impl DiskUsage {
+    #[cfg(unix)]
+    // the statvfs field types differ between platforms
+    #[allow(clippy::useless_conversion)]
+    fn of(path: &Path) -> io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: c_path is NUL-terminated and stat points at writable space for one statvfs
+        if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: statvfs succeeded, so it filled the whole struct in
+        let stat = unsafe { stat.assume_init() };
+
+        let block = u64::from(stat.f_frsize);
+        let blocks = u64::from(stat.f_blocks);
+        Ok(DiskUsage {
+            total: blocks * block,
+            used: blocks.saturating_sub(u64::from(stat.f_bfree)) * block,
+            free: u64::from(stat.f_bavail) * block,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn of(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "disk usage is only available on Unix",
+        ))
+    }
+}
+
+/*
+Print one row per directory: its path relative to the root, the number of
+files directly inside it, and the total size of every file beneath it
+*/
+fn print_overview(
+    root: &TreeNode,
+    opts: &ScanOptions,
+    root_path: &Path,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut rows = Vec::new();
+    collect_overview_rows(root, root_path, &mut rows);
+
+    if matches!(opts.sort_by, SortBy::FileSize) {
+        if opts.reverse {
+            rows.sort_by_key(|row| std::cmp::Reverse(row.2));
+        } else {
+            rows.sort_by_key(|row| row.2);
+        }
+    }
+
+    let width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let mut out = io::BufWriter::new(out);
+    for (path, files, size) in rows {
+        writeln!(
+            out,
+            "{path:<width$}  {files:>6} files  {:>10}",
+            format_size(size)
+        )?;
+    }
+    out.flush()
+}
+
+fn collect_overview_rows(node: &TreeNode, root_path: &Path, rows: &mut Vec<(String, usize, u64)>) {
+    if !node.is_dir {
+        return;
+    }
+
+    let rel = node.path.strip_prefix(root_path).unwrap_or(&node.path);
+    let label = if rel.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        rel.display().to_string()
+    };
+    let direct_files = node
+        .children
+        .iter()
+        .flatten()
+        .filter(|child| !child.is_dir && !child.context)
+        .count();
+    let mut subtree = Stats::default();
+    accumulate_subtree(&mut subtree, node);
+    rows.push((label, direct_files, subtree.size));
+
+    for child in node.children.iter().flatten() {
+        collect_overview_rows(child, root_path, rows);
+    }
+}
+
+fn root_header(root_path: &Path, opts: &ScanOptions) -> String {
+    if let Some(label) = &opts.root_label {
+        return label.clone();
+    }
+    if opts.basename_root {
+        if let Some(name) = root_path.file_name() {
+            return name.to_string_lossy().into_owned();
+        }
+    }
+    shown_path(root_path, opts)
+}
+
+/*
+A path as printed: with --tilde, an absolute path under $HOME starts with ~
+*/
+fn shown_path(path: &Path, opts: &ScanOptions) -> String {
+    let under_home = opts
+        .tilde_home
+        .as_deref()
+        .and_then(|home| path.strip_prefix(home).ok());
+    match under_home {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => Path::new("~").join(rest).display().to_string(),
+        None => path.display().to_string(),
+    }
+}
+
+/*
+How entry_lines colors a name: by entry kind and extension, dimmed for
+--context siblings, in a fixed color such as the --heat gradient, by a
+byte count for --size-heat, or reversed for a --highlight match
+*/
+#[derive(Clone, Copy)]
+enum NameStyle {
+    Kind,
+    Dimmed,
+    Highlighted,
+    Tinted(Color),
+    Sized(u64),
+}
+
+type Painter = fn(&str) -> ColoredString;
+
+/*
+Colors for regular files by extension. --legend prints this same table.
+*/
+const EXTENSION_STYLES: [(&[&str], Painter); 8] = [
+    (&["rs"], |s| s.red().bold()),
+    (&["py"], |s| s.yellow().bold()),
+    (&["c", "cpp", "h", "hpp"], |s| s.cyan().bold()),
+    (&["cs"], |s| s.magenta().bold()),
+    (&["ml", "mli"], |s| s.bright_green().bold()),
+    (&["md"], |s| s.white().italic()),
+    (&["txt"], |s| s.dimmed()),
+    (&["json"], |s| s.bright_yellow().bold()),
+];
+
+// (label, kind, hidden) samples for the kind half of --legend
+const KIND_LEGEND: [(&str, EntryKind, bool); 7] = [
+    ("directory", EntryKind::Dir, false),
+    ("hidden directory", EntryKind::Dir, true),
+    ("symlink", EntryKind::Symlink, false),
+    ("fifo", EntryKind::Fifo, false),
+    ("socket", EntryKind::Socket, false),
+    ("device", EntryKind::CharDevice, false),
+    ("hidden file", EntryKind::File, true),
+];
+
+fn kind_style(name: &str, kind: EntryKind, is_hidden: bool, ext: Option<&str>) -> ColoredString {
+    match kind {
+        EntryKind::Dir if is_hidden => name.blue().bold().dimmed().underline(),
+        EntryKind::Dir => name.blue().bold(),
+        EntryKind::Symlink => name.bright_cyan().italic(),
+        EntryKind::Fifo => name.yellow(),
+        EntryKind::Socket => name.bright_magenta(),
+        EntryKind::BlockDevice | EntryKind::CharDevice => name.bright_yellow().bold(),
+        EntryKind::File if is_hidden => name.dimmed().underline(),
+        EntryKind::File => ext
+            .and_then(|ext| {
+                EXTENSION_STYLES
+                    .iter()
+                    .find(|(exts, _)| exts.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+            })
+            .map_or_else(|| name.normal(), |(_, paint)| paint(name)),
+    }
+}
+
+/*
+The key printed by --legend: each label drawn in the style it describes,
+plus the modes that override those colors when they are active
+*/
+fn legend_lines(opts: &ScanOptions) -> Vec<String> {
+    let kinds: Vec<String> = KIND_LEGEND
+        .iter()
+        .map(|&(label, kind, hidden)| kind_style(label, kind, hidden, None).to_string())
+        .collect();
+    let exts: Vec<String> = EXTENSION_STYLES
+        .iter()
+        .map(|(exts, paint)| {
+            let label: Vec<String> = exts.iter().map(|e| format!(".{e}")).collect();
+            paint(&label.join(" ")).to_string()
+        })
+        .collect();
+
+    let mut lines = vec![
+        "Legend:".to_string(),
+        format!("  {}", kinds.join(", ")),
+        format!("  {}", exts.join(", ")),
+    ];
+    if opts.context > 0 {
+        lines.push(format!("  {} --context sibling", "dimmed:".dimmed()));
+    }
+    if let Some(ref re) = opts.highlight {
+        lines.push(format!(
+            "  {} name matches {re}",
+            "highlighted:".bold().reversed()
+        ));
+    }
+    if opts.heat.is_some() {
+        let newest = "newest".color(Color::TrueColor { r: 0, g: 200, b: 0 });
+        let oldest = "oldest".color(Color::TrueColor { r: 220, g: 0, b: 0 });
+        lines.push(format!("  age (--heat): {newest} to {oldest}"));
+    } else if opts.size_heat {
+        let tiers = [
+            "< 1 KB".dimmed(),
+            "< 1 MB".normal(),
+            "< 100 MB".yellow(),
+            "< 1 GB".red(),
+            ">= 1 GB".red().bold(),
+        ];
+        let tiers: Vec<String> = tiers.iter().map(|t| t.to_string()).collect();
+        lines.push(format!("  sizes (--size-heat): {}", tiers.join(", ")));
+    } else if opts.depth_colors {
+        let levels: Vec<String> = DEPTH_PALETTE
+            .iter()
+            .enumerate()
+            .map(|(i, &color)| format!("{}", (i + 1).to_string().color(color)))
+            .collect();
+        lines.push(format!("  depth (--depth-colors): {}", levels.join(" ")));
+    }
+    lines
+}
+
+fn entry_lines(
+    node: &TreeNode,
+    name: &str,
+    style: NameStyle,
+    opts: &ScanOptions,
+) -> (String, String) {
+    let (path, kind) = (node.path.as_path(), node.kind);
+    let is_hidden = name.starts_with('.') && name != "." && name != "..";
+    let styled_name = match style {
+        NameStyle::Dimmed => name.dimmed(),
+        NameStyle::Highlighted => name.bold().reversed(),
+        NameStyle::Tinted(color) => name.color(color),
+        NameStyle::Sized(bytes) => match bytes {
+            b if b < 1 << 10 => name.dimmed(),
+            b if b < 1 << 20 => name.normal(),
+            b if b < 100 << 20 => name.yellow(),
+            b if b < 1 << 30 => name.red(),
+            _ => name.red().bold(),
+        },
+        NameStyle::Kind => {
+            let ext = path.extension().and_then(|e| e.to_str());
+            kind_style(name, kind, is_hidden, ext)
+        }
+    };
+
+    // --no-metadata promises no stat per entry, so only what was recorded is shown
+    let md = if opts.no_metadata {
+        None
+    } else {
+        fs::metadata(path).ok()
+    };
+    let (size, modified, created) = match md {
+        Some(ref md) => {
+            let size = format_size(md.len());
+            let modified = md
+                .modified()
+                .ok()
+                .map(format_time)
+                .unwrap_or_else(|| "-".into());
+            let created = md
+                .created()
+                .ok()
+                .map(format_time)
+                .unwrap_or_else(|| "-".into());
+            (size, modified, created)
+        }
+        // archive members and entries removed since the scan: use what was recorded
+        None if node.mtime != SystemTime::UNIX_EPOCH => {
+            (format_size(node.size), format_time(node.mtime), "-".into())
+        }
+        None => ("-".into(), "-".into(), "-".into()),
+    };
+
+    let stats_line = format!(
+        "{:<10} {:<12} {:<10} {:<20} {:<10} {:<20}",
+        "Size:", size, "Modified:", modified, "Created:", created
+    );
+
+    let target = match kind {
+        EntryKind::Symlink if !opts.no_metadata => fs::read_link(path)
+            .map(|t| format!(" -> {}", t.display()))
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+
+    (
+        stats_line,
+        format!("{styled_name}{}{target}", kind.indicator()),
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut i = 0;
+    while size >= 1024.0 && i < UNITS.len() - 1 {
+        size /= 1024.0;
+        i += 1;
+    }
+    format!("{:.1} {:<2}", size, UNITS[i])
+}
+
+fn format_time(system_time: SystemTime) -> String {
+    let datetime: DateTime<Local> = system_time.into();
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/*
+Serialise the nodes and write them to the destination, returning the final
+path and the number of bytes. Under dry_run nothing touches the filesystem.
+*/
+fn write_tree_json<T, P>(
+    nodes: &T,
+    dest: Option<P>,
+    indent: usize,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    T: Serialize + ?Sized,
+    P: AsRef<Path>,
+{
+    let raw_path = dest
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("tree.json"));
+
+    let path = ensure_export_path(raw_path, "json");
+
+    let serialised = if indent == 0 {
+        serde_json::to_vec(nodes)
+    } else {
+        let spaces = " ".repeat(indent);
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(spaces.as_bytes());
+        let mut bytes = Vec::new();
+        let mut ser = serde_json::Serializer::with_formatter(&mut bytes, formatter);
+        nodes.serialize(&mut ser).map(|()| bytes)
+    };
+    let json_bytes = serialised.map_err(|e| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::InvalidInput(format!("serialising JSON: {e}")),
+        })
+    })?;
+
+    write_export(path, json_bytes, dry_run)
+}
+
+/*
+Flatten the nodes depth-first so every parent row precedes its children
+*/
+fn flatten_tree<'a>(
+    node: &'a TreeNode,
+    parent: Option<usize>,
+    depth: usize,
+    rows: &mut Vec<FlatNode<'a>>,
+) {
+    let index = rows.len();
+    rows.push(FlatNode {
+        name: &node.name,
+        name_bytes: node.name_bytes.as_deref(),
+        extension: node.extension.as_deref(),
+        path: node.json_path.as_deref(),
+        #[cfg(feature = "sqlite")]
+        full_path: &node.path,
+        parent,
+        depth,
+        size: node.size,
+        mtime: node.mtime,
+        is_dir: node.is_dir,
+        file_count: node.file_count,
+        total_size: node.total_size,
+    });
+    for child in node.children.iter().flatten() {
+        flatten_tree(child, Some(index), depth + 1, rows);
+    }
+}
+
+/*
+Write one row per node (root included) as path,name,is_dir,size,mtime where
+mtime is in seconds since the Unix epoch
+*/
+fn write_tree_csv<P>(
+    tree: &TreeNode,
+    dest: Option<P>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    P: AsRef<Path>,
+{
+    let raw_path = dest
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("tree.csv"));
+
+    let path = ensure_export_path(raw_path, "csv");
+
+    let mut csv = String::from("path,name,is_dir,size,mtime\n");
+    push_csv_rows(tree, &mut csv);
+
+    write_export(path, csv.into_bytes(), dry_run)
+}
+
+fn push_csv_rows(node: &TreeNode, csv: &mut String) {
+    csv.push_str(&format!(
+        "{},{},{},{},{}\n",
+        csv_field(&node.path.display().to_string()),
+        csv_field(&node.name),
+        node.is_dir,
+        node.size,
+        unix_secs(node.mtime)
+    ));
+
+    for child in node.children.iter().flatten() {
+        push_csv_rows(child, csv);
+    }
+}
+
+/*
+Write "<sha256>  <path>" for every regular file in the same format as
+sha256sum, with paths relative to the root so `sha256sum -c` can run from
+there. Symlinks, FIFOs, devices and --context siblings get no line.
+*/
+fn write_manifest<P>(
+    tree: &TreeNode,
+    dest: Option<P>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    P: AsRef<Path>,
+{
+    let raw_path = dest
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("tree.sha256"));
+
+    let path = ensure_export_path(raw_path, "sha256");
+
+    let mut manifest = String::new();
+    push_manifest_lines(tree, &tree.path, &mut manifest)?;
+
+    write_export(path, manifest.into_bytes(), dry_run)
+}
+
+fn push_manifest_lines(
+    node: &TreeNode,
+    root: &Path,
+    manifest: &mut String,
+) -> Result<(), ParseError> {
+    if node.kind == EntryKind::File && !node.context {
+        let digest = sha256_file(&node.path).map_err(|e| {
+            ParseError::Tree(TreeParseError {
+                details: TreeParseType::Io(format!("hashing {}: {e}", node.path.display())),
+            })
+        })?;
+        let name = json_path_for(&node.path, root, &JsonPathMode::Relative)
+            .unwrap_or_default()
+            .display()
+            .to_string();
+        // sha256sum marks lines whose names need escaping with a leading '\'
+        if name.contains(['\\', '\n']) {
+            let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+            manifest.push_str(&format!("\\{digest}  {escaped}\n"));
+        } else {
+            manifest.push_str(&format!("{digest}  {name}\n"));
+        }
+    }
+
+    for child in node.children.iter().flatten() {
+        push_manifest_lines(child, root, manifest)?;
+    }
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/*
+Pipe the tree through `dot -Tsvg`. Without Graphviz, the DOT source is
+written next to the requested file instead, so it can be rendered later.
+*/
+fn write_tree_svg<P>(
+    tree: &TreeNode,
+    dest: Option<P>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    P: AsRef<Path>,
+{
+    let raw_path = dest
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("tree.svg"));
+
+    let path = ensure_export_path(raw_path, "svg");
+    let dot = tree_dot(tree);
+    let rendered = render_svg(&dot);
+    write_rendered_svg(path, dot, rendered, dry_run)
+}
+
+// the SVG when `dot` produced one, the DOT source beside it when `dot` is missing
+fn write_rendered_svg(
+    path: PathBuf,
+    dot: String,
+    rendered: io::Result<Vec<u8>>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError> {
+    match rendered {
+        Ok(svg) => write_export(path, svg, dry_run),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let dot_path = path.with_extension("dot");
+            eprintln!(
+                "mytree: Graphviz 'dot' was not found on PATH; writing {} instead \
+                 (render it with: dot -Tsvg {} -o {})",
+                dot_path.display(),
+                dot_path.display(),
+                path.display()
+            );
+            write_export(dot_path, dot.into_bytes(), dry_run)
+        }
+        Err(e) => Err(ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!("rendering {}: {e}", path.display())),
+        })),
+    }
+}
+
+fn render_svg(dot: &str) -> io::Result<Vec<u8>> {
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // dot reads all of its input before writing, so this cannot deadlock
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(dot.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "dot failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/*
+Graphviz source with one box per node (folders for directories) and an edge
+from each directory to its children, numbered in --json-flat row order
+*/
+fn tree_dot(tree: &TreeNode) -> String {
+    let mut rows = Vec::new();
+    flatten_tree(tree, None, 0, &mut rows);
+
+    let mut dot = String::from("digraph tree {\n    rankdir=LR;\n    node [shape=box];\n");
+    for (id, row) in rows.iter().enumerate() {
+        let label = row.name.replace('\\', "\\\\").replace('"', "\\\"");
+        let shape = if row.is_dir { " shape=folder" } else { "" };
+        dot.push_str(&format!("    n{id} [label=\"{label}\"{shape}];\n"));
+        if let Some(parent) = row.parent {
+            dot.push_str(&format!("    n{parent} -> n{id};\n"));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn write_tree_sexp<P>(
+    tree: &TreeNode,
+    dest: Option<P>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    P: AsRef<Path>,
+{
+    let raw_path = dest
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("tree.sexp"));
+
+    let path = ensure_export_path(raw_path, "sexp");
+    let mut sexp = String::new();
+    push_sexp(tree, 0, &mut sexp);
+    sexp.push('\n');
+    write_export(path, sexp.into_bytes(), dry_run)
+}
+
+/*
+(dir "name" children...) or (file "name" size), one node per line indented
+by depth, with '"' and '\' escaped inside the quoted names
+*/
+fn push_sexp(node: &TreeNode, depth: usize, out: &mut String) {
+    let name = node.name.replace('\\', "\\\\").replace('"', "\\\"");
+    out.push_str(&"  ".repeat(depth));
+    if !node.is_dir {
+        out.push_str(&format!("(file \"{name}\" {})", node.size));
+        return;
+    }
+    out.push_str(&format!("(dir \"{name}\""));
+    for child in node.children.iter().flatten() {
+        out.push('\n');
+        push_sexp(child, depth + 1, out);
+    }
+    out.push(')');
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/*
+Write every node into a fresh entries table, replacing any earlier one.
+id is the depth-first row index and parent refers to it, as in --json-flat.
+The returned count is the number of rows rather than bytes.
+*/
+#[cfg(feature = "sqlite")]
+fn write_tree_sqlite<P>(
+    tree: &TreeNode,
+    dest: Option<P>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    P: AsRef<Path>,
+{
+    let raw_path = dest
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("tree.db"));
+
+    let path = ensure_export_path(raw_path, "db");
+
+    if dry_run {
+        let mut rows = Vec::new();
+        flatten_tree(tree, None, 0, &mut rows);
+        return Ok((path, rows.len()));
+    }
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| {
+            ParseError::Tree(TreeParseError {
+                details: TreeParseType::Io(format!("creating {parent:?}: {e}")),
+            })
+        })?;
+    }
+
+    let sqlite_err = |e: rusqlite::Error| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!("writing {path:?}: {e}")),
+        })
+    };
+
+    let mut conn = rusqlite::Connection::open(&path).map_err(sqlite_err)?;
+    let taken: bool = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'entries')",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(sqlite_err)?;
+    if taken {
+        return Err(ParseError::Tree(TreeParseError {
+            details: TreeParseType::InvalidInput(format!(
+                "{path:?} already has an entries table; refusing to replace it"
+            )),
+        }));
+    }
+    let rows = insert_entries(&mut conn, tree).map_err(sqlite_err)?;
+    Ok((path, rows))
+}
+
+/*
+Create the entries table on conn and fill it from the tree, returning the
+number of rows. An existing entries table is an error, never replaced.
+*/
+#[cfg(feature = "sqlite")]
+fn insert_entries(conn: &mut rusqlite::Connection, tree: &TreeNode) -> rusqlite::Result<usize> {
+    let mut rows = Vec::new();
+    flatten_tree(tree, None, 0, &mut rows);
+
+    // one transaction for the whole tree; per-row commits are far slower
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        "CREATE TABLE entries (
+             id INTEGER PRIMARY KEY,
+             path TEXT NOT NULL,
+             name TEXT NOT NULL,
+             parent INTEGER REFERENCES entries(id),
+             depth INTEGER NOT NULL,
+             size INTEGER NOT NULL,
+             mtime INTEGER NOT NULL,
+             is_dir INTEGER NOT NULL
+         );",
+    )?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO entries (id, path, name, parent, depth, size, mtime, is_dir)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for (id, row) in rows.iter().enumerate() {
+            insert.execute(rusqlite::params![
+                id as i64,
+                row.full_path.display().to_string(),
+                row.name,
+                row.parent.map(|p| p as i64),
+                row.depth as i64,
+                row.size as i64,
+                unix_secs(row.mtime) as i64,
+                row.is_dir,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(rows.len())
+}
+
+fn csv_field(raw: &str) -> String {
+    if raw.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", raw.replace('"', "\"\""))
+    } else {
+        raw.to_string()
+    }
+}
+
+/*
+Write the --web viewer: the nested --json tree next to a page that fetches
+it. Browsers refuse fetch() from file:// URLs, so the page says to serve
+the directory when that happens.
+*/
+fn write_web_viewer(
+    tree: &TreeNode,
+    dir: &Path,
+    opts: &ScanOptions,
+) -> Result<(PathBuf, usize), ParseError> {
+    let (_, json_len) = write_tree_json(
+        std::slice::from_ref(tree),
+        Some(dir.join("tree.json")),
+        opts.json_indent,
+        opts.dry_run,
+    )?;
+    let (_, html_len) = write_export(
+        dir.join("index.html"),
+        WEB_VIEWER_HTML.as_bytes().to_vec(),
+        opts.dry_run,
+    )?;
+    Ok((dir.to_path_buf(), json_len + html_len))
+}
+
+const WEB_VIEWER_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>mytree</title>
+<style>
+  body { font: 14px/1.5 ui-monospace, monospace; margin: 2em; }
+  details > :not(summary) { margin-left: 1.5em; }
+  summary { cursor: pointer; font-weight: bold; }
+  .file { margin-left: 1.5em; }
+  .size { color: #888; margin-left: 1em; font-weight: normal; }
+</style>
+</head>
+<body>
+<div id="tree">Loading tree.json...</div>
+<script>
+function formatSize(bytes) {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) { bytes /= 1024; i++; }
+  return bytes.toFixed(1) + " " + units[i];
+}
+
+function totalSize(node) {
+  if (!node.is_dir) return node.size;
+  return (node.children || []).reduce((sum, child) => sum + totalSize(child), 0);
+}
+
+function label(node) {
+  const size = document.createElement("span");
+  size.className = "size";
+  size.textContent = formatSize(totalSize(node));
+  return [document.createTextNode(node.name), size];
+}
+
+function render(node, depth) {
+  if (!node.is_dir) {
+    const div = document.createElement("div");
+    div.className = "file";
+    div.append(...label(node));
+    return div;
+  }
+  const details = document.createElement("details");
+  details.open = depth < 1;
+  const summary = document.createElement("summary");
+  summary.append(...label(node));
+  details.append(summary);
+  for (const child of node.children || []) details.append(render(child, depth + 1));
+  return details;
+}
+
+fetch("tree.json")
+  .then((response) => response.json())
+  .then((nodes) => {
+    const root = document.getElementById("tree");
+    root.textContent = "";
+    for (const node of nodes) root.append(render(node, 0));
+  })
+  .catch((error) => {
+    document.getElementById("tree").textContent =
+      "Could not load tree.json (" + error + "). Browsers block this for local files; " +
+      "serve the directory instead, e.g. python3 -m http.server";
+  });
+</script>
+</body>
+</html>
+"#;
+
+fn write_export(
+    path: PathBuf,
+    bytes: Vec<u8>,
+    dry_run: bool,
+) -> Result<(PathBuf, usize), ParseError> {
+    let len = bytes.len();
+
+    if dry_run {
+        return Ok((path, len));
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| {
+            ParseError::Tree(TreeParseError {
+                details: TreeParseType::Io(format!("creating {parent:?}: {e}")),
+            })
+        })?;
+    }
+
+    fs::write(&path, bytes).map_err(|e| {
+        ParseError::Tree(TreeParseError {
+            details: TreeParseType::Io(format!("writing {path:?}: {e}")),
+        })
+    })?;
+
+    Ok((path, len))
+}
+
+fn ensure_export_path<P: AsRef<Path>>(p: P, ext: &str) -> PathBuf {
+    let path = p.as_ref();
+
+    if path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case(ext))
+        == Some(true)
+    {
+        return path.to_path_buf();
+    }
+
+    let mut dir = path.to_path_buf();
+    dir.push(format!("tree.{ext}"));
+    dir
+}
+
+fn export_dest(dest_raw: &str) -> Option<&Path> {
+    if dest_raw.trim().is_empty() {
+        None
+    } else {
+        Some(Path::new(dest_raw))
+    }
+}
+
+fn report_export(kind: &str, path: &Path, len: usize, dry_run: bool) {
+    if dry_run {
+        eprintln!(
+            "Would write {len} bytes of {kind} to {} (dry run)",
+            path.display()
+        );
+    } else {
+        eprintln!("Wrote directory tree to {}", path.display());
+    }
+}
+
+fn write_annotated_json<T>(
+    value: &T,
+    annotation: Option<&Annotation>,
+    dest: Option<&Path>,
+    opts: &ScanOptions,
+) -> Result<(PathBuf, usize), ParseError>
+where
+    T: Serialize + ?Sized,
+{
+    let (indent, dry_run) = (opts.json_indent, opts.dry_run);
+    match annotation {
+        Some(meta) => write_tree_json(&Annotated { meta, tree: value }, dest, indent, dry_run),
+        None => write_tree_json(value, dest, indent, dry_run),
+    }
+}
+
+fn strip_context(node: &mut TreeNode) {
+    if let Some(children) = node.children.as_mut() {
+        children.retain(|child| !child.context);
+        children.iter_mut().for_each(strip_context);
+    }
+}
+
+/*
+Run every requested exporter over the already-built tree
+*/
+fn emit_exports(
+    tree: &TreeNode,
+    opts: &ScanOptions,
+    annotation: Option<&Annotation>,
+) -> Result<(), ParseError> {
+    // --context only decorates the terminal view
+    let stripped;
+    let tree = if opts.context > 0 {
+        let mut copy = tree.clone();
+        strip_context(&mut copy);
+        stripped = copy;
+        &stripped
+    } else {
+        tree
+    };
+
+    if let Some(ref raw_dest) = opts.write_json {
+        let nodes = if opts.json_children_only {
+            tree.children.as_deref().unwrap_or_default()
+        } else {
+            std::slice::from_ref(tree)
+        };
+        let (path, len) = if opts.json_flat {
+            // children-only keeps true depths but leaves the top rows parentless
+            let depth = usize::from(opts.json_children_only);
+            let mut rows = Vec::new();
+            for node in nodes {
+                flatten_tree(node, None, depth, &mut rows);
+            }
+            write_annotated_json(&rows[..], annotation, export_dest(raw_dest), opts)?
+        } else {
+            write_annotated_json(nodes, annotation, export_dest(raw_dest), opts)?
+        };
+        report_export("JSON", &path, len, opts.dry_run);
+    }
+
+    if let Some(ref raw_dest) = opts.write_csv {
+        let (path, len) = write_tree_csv(tree, export_dest(raw_dest), opts.dry_run)?;
+        report_export("CSV", &path, len, opts.dry_run);
+    }
+
+    if let Some(ref raw_dest) = opts.manifest {
+        let (path, len) = write_manifest(tree, export_dest(raw_dest), opts.dry_run)?;
+        report_export("manifest", &path, len, opts.dry_run);
+    }
+
+    if let Some(ref raw_dest) = opts.svg {
+        let (path, len) = write_tree_svg(tree, export_dest(raw_dest), opts.dry_run)?;
+        report_export("SVG", &path, len, opts.dry_run);
+    }
+
+    if let Some(ref raw_dest) = opts.sexp {
+        let (path, len) = write_tree_sexp(tree, export_dest(raw_dest), opts.dry_run)?;
+        report_export("S-expression", &path, len, opts.dry_run);
+    }
+
+    if let Some(ref dir) = opts.web {
+        let (path, len) = write_web_viewer(tree, Path::new(dir), opts)?;
+        report_export("web viewer", &path, len, opts.dry_run);
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(ref raw_dest) = opts.write_sqlite {
+        let (path, rows) = write_tree_sqlite(tree, export_dest(raw_dest), opts.dry_run)?;
+        if opts.dry_run {
+            eprintln!(
+                "Would write {rows} rows of SQLite to {} (dry run)",
+                path.display()
+            );
+        } else {
+            report_export("SQLite", &path, rows, false);
+        }
+    }
+
+    Ok(())
+}
+
+fn report_walk_errors(errors: &[TreeParseError]) {
+    if errors.is_empty() {
+        return;
+    }
+    let noun = if errors.len() == 1 { "error" } else { "errors" };
+    eprintln!("mytree: {} {noun} occurred:", errors.len());
+    for err in errors {
+        eprintln!("  {err}");
+    }
+}
+
+pub fn run(args: Args) -> io::Result<()> {
+    let (path, opts) = create_scan_options_from_args(args)?;
+    run_with_options(&path, &opts, &mut io::stdout().lock())
+}
+
+fn run_with_options(path: &Path, opts: &ScanOptions, out: &mut dyn Write) -> io::Result<()> {
+    let mut state = WalkState::new(opts);
+    state.collect_errors = !opts.strict;
+    let mut tree = build_directory_tree(path, opts, &mut state)?;
+    if let Some(ref glob) = opts.root_glob {
+        if !retain_glob_matches(&mut tree, path, glob) {
+            return Err(ParseError::Tree(TreeParseError {
+                details: TreeParseType::InvalidInput(format!(
+                    "no paths under {} match the glob {}",
+                    path.display(),
+                    glob.raw
+                )),
+            })
+            .into());
+        }
+    }
+
+    if !opts.count_extensions.is_empty() {
+        for ext in &opts.count_extensions {
+            writeln!(out, "{}", count_extension(&tree, ext, opts))?;
+        }
+        report_walk_errors(&state.errors);
+        return Ok(());
+    }
+    if let Some(ref snapshot) = opts.compare {
+        let diff = compare_snapshot(&tree, snapshot)?;
+        for line in &diff {
+            writeln!(out, "{line}")?;
+        }
+        report_walk_errors(&state.errors);
+        if !diff.is_empty() {
+            return Err(ParseError::Tree(TreeParseError {
+                details: TreeParseType::InvalidInput(format!(
+                    "{} differences against {}",
+                    diff.len(),
+                    snapshot.display()
+                )),
+            })
+            .into());
+        }
+        return Ok(());
+    }
+    if opts.broken_links {
+        let mut out = io::BufWriter::new(&mut *out);
+        for (link, target) in broken_links(&tree, opts) {
+            writeln!(out, "{} -> {}", shown_path(link, opts), target.display())?;
+        }
+        out.flush()?;
+        report_walk_errors(&state.errors);
+        return Ok(());
+    }
+    if opts.index {
+        let mut out = io::BufWriter::new(&mut *out);
+        for dir in directory_index(&tree, opts) {
+            writeln!(out, "{}", dir.display())?;
+        }
+        out.flush()?;
+        report_walk_errors(&state.errors);
+        return Ok(());
+    }
+    if opts.paths_only {
+        let mut out = io::BufWriter::new(&mut *out);
+        for path in file_paths(&tree, opts) {
+            writeln!(out, "{}", shown_path(path, opts))?;
+        }
+        out.flush()?;
+        report_walk_errors(&state.errors);
+        return Ok(());
+    }
+
+    if let Some(sub) = opts
+        .summary_for
+        .as_deref()
+        .filter(|sub| find_node(&tree, sub).is_none())
+    {
+        return Err(ParseError::Tree(TreeParseError {
+            details: TreeParseType::InvalidInput(format!(
+                "{} is not in the tree (filtered out or beyond --max-depth)",
+                sub.display()
+            )),
+        })
+        .into());
+    }
+
+    let annotation = opts.annotate.then(|| Annotation::new(path, opts));
+    emit_exports(&tree, opts, annotation.as_ref())?;
+
+    if let Some(annotation) = annotation.as_ref().filter(|_| !opts.quiet) {
+        writeln!(out, "{}", annotation.header())?;
+    }
+    if opts.overview && !opts.quiet {
+        print_overview(&tree, opts, path, out)?;
+    } else if !opts.quiet {
+        print_ascii_tree(&tree, opts, path, &state.filtered, out)?;
+    }
+    if let Some(limit) = opts.depth_weighted {
+        for line in depth_weighted_lines(&tree, limit, opts) {
+            writeln!(out, "{line}")?;
+        }
+    }
+    if opts.per_top_level {
+        for line in per_top_level_lines(&tree, opts) {
+            writeln!(out, "{line}")?;
+        }
+    }
+    if let Some(metric) = opts.chart {
+        for line in extension_chart(&tree, metric, opts) {
+            writeln!(out, "{line}")?;
+        }
+    }
+    if opts.summary_json {
+        let summary = serde_json::to_string(&tree_stats(&tree, opts)).map_err(io::Error::other)?;
+        writeln!(out, "{summary}")?;
+    }
+    if state.timed_out {
+        eprintln!("mytree: scan timed out; the results above are partial");
+    }
+    if let Some(warning) = depth_warning(state.deepest, opts) {
+        eprintln!("{warning}");
+    }
+    report_walk_errors(&state.errors);
+
+    if opts.fail_on_empty && tree_stats(&tree, opts).files == 0 {
+        return Err(ParseError::Tree(TreeParseError {
+            details: TreeParseType::InvalidInput(format!(
+                "no files under {} matched the filters",
+                path.display()
+            )),
+        })
+        .into());
+    }
+    Ok(())
 }
 
-/*
-Return a vector of ordered row-level entries at a point in the directory
-*/
-fn create_ordered_row_level_entries(
-    path: &Path,
-    opts: &PrintOptions,
-) -> Result<Vec<EntryMeta>, ParseError> {
-    let iter = fs::read_dir(path).map_err(|e| {
-        ParseError::Tree(TreeParseError {
-            details: TreeParseType::Io(format!("error reading directory {}: {e}", path.display())),
-        })
-    })?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+    use tempfile::TempDir;
+
+    // colored's override is process-wide, so runs that print take turns
+    static OUTPUT: Mutex<()> = Mutex::new(());
+
+    fn lock_output(colors: bool) -> MutexGuard<'static, ()> {
+        let guard = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+        colored::control::set_override(colors);
+        guard
+    }
+
+    /*
+    A temporary tree holding the given files, parents created on the way;
+    a path ending in '/' is created as an empty directory
+    */
+    fn fixture(entries: &[(&str, &str)]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, contents) in entries {
+            let full = dir.path().join(path);
+            if path.ends_with('/') {
+                fs::create_dir_all(&full).unwrap();
+            } else {
+                fs::create_dir_all(full.parent().unwrap()).unwrap();
+                fs::write(&full, contents).unwrap();
+            }
+        }
+        dir
+    }
+
+    fn git(repo: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args([
+                "-c",
+                "commit.gpgsign=false",
+                "-c",
+                "init.defaultBranch=main",
+            ])
+            .args(args)
+            .current_dir(repo)
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[cfg(unix)]
+    fn make_fifo(path: &Path) {
+        let status = std::process::Command::new("mkfifo")
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "mkfifo {path:?} failed");
+    }
+
+    fn parse(root: &Path, flags: &[&str]) -> Result<Args, clap::Error> {
+        let argv = [OsStr::new("mytree"), root.as_os_str()]
+            .into_iter()
+            .chain(flags.iter().map(OsStr::new));
+        Args::try_parse_from(argv)
+    }
+
+    fn options(root: &Path, flags: &[&str]) -> (PathBuf, ScanOptions) {
+        create_scan_options_from_args(parse(root, flags).unwrap()).unwrap()
+    }
+
+    fn tree(root: &Path, flags: &[&str]) -> TreeNode {
+        let (path, opts) = options(root, flags);
+        build_directory_tree(&path, &opts, &mut WalkState::new(&opts)).unwrap()
+    }
+
+    // what a run prints to stdout, and how it ended
+    fn try_run(root: &Path, flags: &[&str]) -> (String, io::Result<()>) {
+        let (path, opts) = options(root, flags);
+        let _output = lock_output(false);
+        let mut out = Vec::new();
+        let result = run_with_options(&path, &opts, &mut out);
+        (String::from_utf8(out).unwrap(), result)
+    }
+
+    // the output with ANSI styling left in
+    fn run_colored(root: &Path, flags: &[&str]) -> String {
+        let (path, opts) = options(root, flags);
+        let _output = lock_output(true);
+        let mut out = Vec::new();
+        run_with_options(&path, &opts, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // the ANSI escape sequence a row puts right before name, empty when unstyled
+    fn style_of<'a>(out: &'a str, name: &str) -> &'a str {
+        let row = out.lines().find(|line| line.contains(name)).unwrap();
+        let before = &row[..row.find(name).unwrap()];
+        before.rfind('\x1b').map_or("", |at| &before[at..])
+    }
+
+    fn run_ok(root: &Path, flags: &[&str]) -> String {
+        let (out, result) = try_run(root, flags);
+        result.unwrap();
+        out
+    }
+
+    // the names printed in the tree, in order, without the root or the summary
+    fn listed(out: &str) -> Vec<&str> {
+        out.lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.trim_start_matches(|c: char| " │├└┌─".contains(c)))
+            .collect()
+    }
+
+    // the totals printed by -q --summary-json
+    fn summary(root: &Path, flags: &[&str]) -> serde_json::Value {
+        let mut flags = flags.to_vec();
+        flags.extend(["-q", "--summary-json"]);
+        serde_json::from_str(&run_ok(root, &flags)).unwrap()
+    }
+
+    // write --json to a scratch directory and read it back
+    fn json_export(root: &Path, flags: &[&str]) -> serde_json::Value {
+        let dest = tempfile::tempdir().unwrap();
+        let file = dest.path().join("tree.json");
+        let mut flags = flags.to_vec();
+        flags.extend(["-q", "--json", file.to_str().unwrap()]);
+        run_ok(root, &flags);
+        serde_json::from_slice(&fs::read(&file).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn json_paths_are_relative_to_the_root() {
+        let dir = fixture(&[("src/main.rs", "fn main() {}")]);
+        let json = json_export(dir.path(), &["--json-relative-paths"]);
+        let root = &json[0];
+        assert_eq!(root["path"], ".");
+        assert_eq!(root["children"][0]["path"], "src");
+        assert_eq!(root["children"][0]["children"][0]["path"], "src/main.rs");
+    }
+
+    #[test]
+    fn json_paths_can_be_left_out() {
+        let dir = fixture(&[("src/main.rs", "fn main() {}")]);
+        let json = json_export(dir.path(), &["--json-no-path"]);
+        let src = &json[0]["children"][0];
+        assert!(json[0].get("path").is_none());
+        assert!(src.get("path").is_none());
+        assert!(src["children"][0].get("path").is_none());
+        assert_eq!(src["children"][0]["name"], "main.rs");
+    }
+
+    #[test]
+    fn filtered_count_matches_the_entries_left_out() {
+        let dir = fixture(&[
+            ("a.rs", ""),
+            ("b.txt", ""),
+            ("notes/c.md", ""),
+            ("src/d.rs", ""),
+        ]);
+        let out = run_ok(dir.path(), &["-e", "rs", "--show-filtered-count"]);
+        assert!(out.contains("(2 entries hidden by filters)"), "{out}");
+    }
+
+    #[test]
+    fn regex_mode_all_needs_every_pattern() {
+        let dir = fixture(&[("main.rs", ""), ("main_test.rs", ""), ("lib_test.py", "")]);
+        let out = run_ok(
+            dir.path(),
+            &["-r", "^main", "-r", "test", "--regex-mode", "all"],
+        );
+        assert_eq!(listed(&out), ["main_test.rs"]);
+    }
+
+    #[test]
+    fn regex_mode_any_needs_one_pattern() {
+        let dir = fixture(&[
+            ("main.rs", ""),
+            ("main_test.rs", ""),
+            ("lib_test.py", ""),
+            ("x", ""),
+        ]);
+        let out = run_ok(
+            dir.path(),
+            &["-r", "^main", "-r", "test", "--regex-mode", "any"],
+        );
+        assert_eq!(listed(&out), ["lib_test.py", "main.rs", "main_test.rs"]);
+    }
+
+    #[test]
+    fn include_keeps_only_matching_files() {
+        let dir = fixture(&[
+            ("a.rs", ""),
+            ("b.txt", ""),
+            ("src/c.rs", ""),
+            ("docs/d.md", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--include", "*.rs"]);
+        assert_eq!(listed(&out), ["a.rs", "src", "c.rs"]);
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let dir = fixture(&[("a.rs", ""), ("b.txt", ""), ("src/c.rs", "")]);
+        let out = run_ok(dir.path(), &["--include", "*.rs", "--exclude", "c.rs"]);
+        assert_eq!(listed(&out), ["a.rs"]);
+    }
+
+    #[test]
+    fn depth_range_shows_only_the_middle_levels() {
+        let dir = fixture(&[
+            ("top.txt", ""),
+            ("a/one.txt", ""),
+            ("a/b/two.txt", ""),
+            ("a/b/c/three.txt", ""),
+            ("a/b/c/d/four.txt", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--min-depth", "2", "--max-depth", "3"]);
+        assert_eq!(listed(&out), ["a/b", "c", "two.txt", "a/one.txt"]);
+    }
+
+    #[test]
+    fn header_uses_the_chosen_root_label() {
+        let dir = fixture(&[("a.txt", "")]);
+        let out = run_ok(dir.path(), &["--root-label", "."]);
+        assert_eq!(out.lines().next(), Some("."));
+
+        let base = dir.path().file_name().unwrap().to_str().unwrap();
+        let out = run_ok(dir.path(), &["--basename-root"]);
+        assert_eq!(out.lines().next(), Some(base));
+    }
+
+    #[test]
+    fn exclude_patterns_are_read_from_a_reader() {
+        let stdin = io::Cursor::new("# build output\ntarget\n\n  *.log  \n");
+        let patterns = read_exclude_patterns(stdin).unwrap();
+        assert_eq!(patterns, ["target", "*.log"]);
+
+        let dir = fixture(&[("target/debug/app", ""), ("run.log", ""), ("main.rs", "")]);
+        let mut opts = ScanOptions::default();
+        for pattern in &patterns {
+            opts = opts.exclude(pattern).unwrap();
+        }
+        let root = build_directory_tree(dir.path(), &opts, &mut WalkState::new(&opts)).unwrap();
+        let names: Vec<_> = root.children.unwrap().into_iter().map(|n| n.name).collect();
+        assert_eq!(names, ["main.rs"]);
+    }
+
+    #[test]
+    fn dry_run_writes_nothing_but_reports_the_real_size() {
+        let dir = fixture(&[("src/main.rs", "fn main() {}"), ("README.md", "# hi")]);
+        let root = tree(dir.path(), &[]);
+        let dest = tempfile::tempdir().unwrap();
+        let file = dest.path().join("out.json");
+
+        let (path, would) =
+            write_tree_json(std::slice::from_ref(&root), Some(&file), 2, true).unwrap();
+        assert_eq!(path, file);
+        assert!(!file.exists());
+
+        let (_, wrote) =
+            write_tree_json(std::slice::from_ref(&root), Some(&file), 2, false).unwrap();
+        assert_eq!(would, wrote);
+        assert_eq!(fs::metadata(&file).unwrap().len(), would as u64);
+    }
+
+    #[test]
+    fn pinned_entries_come_first_in_the_given_order() {
+        let dir = fixture(&[
+            ("a.txt", ""),
+            ("docs/guide.md", ""),
+            ("src/lib.rs", ""),
+            ("tests/it.rs", ""),
+            ("z.txt", ""),
+        ]);
+        let flags = ["-1", "--pin", "src", "--pin", "tests", "--pin", "docs"];
+        let out = run_ok(dir.path(), &flags);
+        assert_eq!(listed(&out), ["src", "tests", "docs", "a.txt", "z.txt"]);
+    }
+
+    #[test]
+    fn collapse_joins_single_child_chains_only() {
+        let dir = fixture(&[("a/b/c/f.txt", ""), ("x/y.txt", ""), ("x/z/w.txt", "")]);
+        let out = run_ok(dir.path(), &["--collapse"]);
+        assert_eq!(listed(&out), ["a/b/c", "f.txt", "x", "y.txt", "z", "w.txt"]);
+    }
+
+    #[test]
+    fn collapse_keeps_the_summary_totals() {
+        let dir = fixture(&[("a/b/c/f.txt", "data"), ("x/y.txt", "")]);
+        let summary = |out: &str| {
+            out.lines()
+                .skip_while(|l| !l.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let plain = run_ok(dir.path(), &["--breakdown"]);
+        let collapsed = run_ok(dir.path(), &["--breakdown", "--collapse"]);
+        assert!(plain.contains("4 directories, 2 files"), "{plain}");
+        assert_eq!(summary(&plain), summary(&collapsed));
+    }
+
+    #[test]
+    fn count_badge_shows_files_beneath_each_directory() {
+        let dir = fixture(&[("src/a.rs", ""), ("src/b.rs", ""), ("src/bin/c.rs", "")]);
+        let out = run_ok(dir.path(), &["--count-badge"]);
+        assert_eq!(
+            listed(&out),
+            ["src (3 files)", "a.rs", "b.rs", "bin (1 files)", "c.rs"]
+        );
+    }
+
+    #[test]
+    fn several_exports_are_written_in_one_run() {
+        let dir = fixture(&[("src/main.rs", "fn main() {}")]);
+        let dest = tempfile::tempdir().unwrap();
+        let json = dest.path().join("tree.json");
+        let csv = dest.path().join("tree.csv");
+        let flags = [
+            "--json",
+            json.to_str().unwrap(),
+            "--csv",
+            csv.to_str().unwrap(),
+        ];
+        let out = run_ok(dir.path(), &flags);
+
+        assert_eq!(listed(&out), ["src", "main.rs"]);
+        let nodes: serde_json::Value = serde_json::from_slice(&fs::read(&json).unwrap()).unwrap();
+        assert_eq!(nodes[0]["children"][0]["name"], "src");
+        let rows = fs::read_to_string(&csv).unwrap();
+        assert!(rows.starts_with("path,name,is_dir,size,mtime\n"));
+        assert_eq!(rows.lines().count(), 4);
+    }
+
+    #[test]
+    fn json_children_only_drops_the_root_object() {
+        let dir = fixture(&[("a.txt", ""), ("src/main.rs", "")]);
+        let json = json_export(dir.path(), &["--json-children-only"]);
+        let names: Vec<_> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["a.txt", "src"]);
+        assert_eq!(json[1]["children"][0]["name"], "main.rs");
+    }
+
+    #[test]
+    fn locale_sort_puts_accents_with_their_base_letter() {
+        let dir = fixture(&[("eclair", ""), ("éa", ""), ("f", ""), ("Zed", "")]);
+        let out = run_ok(dir.path(), &[]);
+        assert_eq!(listed(&out), ["eclair", "f", "Zed", "éa"]);
+        let out = run_ok(dir.path(), &["--locale-sort"]);
+        assert_eq!(listed(&out), ["éa", "eclair", "f", "Zed"]);
+    }
+
+    #[test]
+    fn locale_sort_handles_decomposed_names() {
+        // "e" followed by U+0301 COMBINING ACUTE ACCENT, as macOS writes "é"
+        let nfd = "e\u{301}a";
+        let dir = fixture(&[("ez", ""), (nfd, ""), ("eb", "")]);
+        let out = run_ok(dir.path(), &["--locale-sort"]);
+        assert_eq!(listed(&out), [nfd, "eb", "ez"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inode_column_shows_the_file_inode() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = fixture(&[("a.txt", "x")]);
+        let root_ino = fs::metadata(dir.path()).unwrap().ino();
+        let ino = fs::metadata(dir.path().join("a.txt")).unwrap().ino();
+        let out = run_ok(dir.path(), &["-l", "--inode"]);
+        let shown: Vec<u64> = out
+            .lines()
+            .filter(|line| line.contains("Inode:"))
+            .map(|line| line.split_whitespace().last().unwrap().parse().unwrap())
+            .collect();
+        assert!(ino > 0);
+        assert_eq!(shown, [root_ino, ino]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dedup_links_counts_a_hard_link_once() {
+        let dir = fixture(&[("a.txt", &"x".repeat(100))]);
+        fs::hard_link(dir.path().join("a.txt"), dir.path().join("b.txt")).unwrap();
+
+        assert_eq!(summary(dir.path(), &[])["bytes"], 200);
+        let deduped = summary(dir.path(), &["--dedup-links"]);
+        assert_eq!(deduped["bytes"], 100);
+        assert_eq!(deduped["files"], 2);
+        let out = run_ok(dir.path(), &["--dedup-links"]);
+        assert_eq!(listed(&out), ["a.txt", "b.txt [hard link]"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_labelled_by_kind() {
+        let dir = fixture(&[("plain.txt", "")]);
+        make_fifo(&dir.path().join("pipe"));
+        let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("sock")).unwrap();
+
+        let out = run_ok(dir.path(), &[]);
+        assert_eq!(listed(&out), ["pipe [fifo]", "plain.txt", "sock [socket]"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stay_on_skips_entries_from_other_devices() {
+        let dir = fixture(&[("a.txt", ""), ("sub/b.txt", "")]);
+        let anchor = dir.path().join("sub");
+        let out = run_ok(dir.path(), &["--stay-on", anchor.to_str().unwrap()]);
+        assert_eq!(listed(&out), ["a.txt", "sub", "b.txt"]);
+
+        // where there is a /proc it is its own filesystem, so nothing here lives on it
+        let here = device_of(&fs::metadata(dir.path()).unwrap());
+        if fs::metadata("/proc").is_ok_and(|md| device_of(&md) != here) {
+            let out = run_ok(dir.path(), &["--stay-on", "/proc", "--show-filtered-count"]);
+            assert!(listed(&out).is_empty(), "{out}");
+            assert!(out.contains("(2 entries hidden by filters)"), "{out}");
+        }
+    }
+
+    #[test]
+    fn buffered_output_matches_the_line_by_line_layout() {
+        let dir = fixture(&[
+            ("README.md", "# demo\n"),
+            ("src/lib.rs", ""),
+            ("src/main.rs", "fn main() {}\n"),
+        ]);
+        let out = run_ok(dir.path(), &["--root-label", "demo"]);
+        let expected = [
+            "demo",
+            "│   ├── README.md",
+            "    └── src",
+            "    │   ├── lib.rs",
+            "        └── main.rs",
+            "",
+            "1 directories, 3 files, 20.0 B  bytes total",
+        ];
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn shared_prefix_buffer_restores_each_level() {
+        let dir = fixture(&[("a/b/c.txt", ""), ("a/d.txt", ""), ("e.txt", "")]);
+        let rows = |flags: &[&str]| {
+            let mut flags = flags.to_vec();
+            flags.extend(["--root-label", "r"]);
+            let out = run_ok(dir.path(), &flags);
+            out.lines()
+                .take_while(|l| !l.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rows(&[]),
+            [
+                "r",
+                "│   ├── a",
+                "│   │   ├── b",
+                "│   │       └── c.txt",
+                "│       └── d.txt",
+                "    └── e.txt",
+            ]
+        );
+        assert_eq!(
+            rows(&["--indent", "2"]),
+            [
+                "r",
+                "│ ├ a",
+                "│ │ ├ b",
+                "│ │   └ c.txt",
+                "│   └ d.txt",
+                "  └ e.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn run_accepts_a_relative_path() {
+        fn named(path: &Path) -> Vec<&OsStr> {
+            path.components()
+                .filter_map(|part| match part {
+                    std::path::Component::Normal(name) => Some(name),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        // climb from the working directory to the filesystem root, then down into the fixture
+        let dir = fixture(&[("a.txt", "hi")]);
+        let cwd = std::env::current_dir().unwrap();
+        let mut relative: PathBuf = named(&cwd).iter().map(|_| "..").collect();
+        relative.extend(named(dir.path()));
+        let relative = relative.as_path();
+        assert!(relative.is_relative());
+
+        let out = run_ok(relative, &[]);
+        assert_eq!(out.lines().next(), relative.to_str());
+        assert_eq!(listed(&out), ["a.txt"]);
+    }
+
+    #[test]
+    fn walk_yields_the_built_tree_in_preorder() {
+        fn flatten(node: &TreeNode, paths: &mut Vec<PathBuf>) {
+            for child in node.children.iter().flatten() {
+                paths.push(child.path.clone());
+                flatten(child, paths);
+            }
+        }
+
+        let dir = fixture(&[
+            ("b.txt", ""),
+            ("a/x.rs", ""),
+            ("a/y/z.md", ""),
+            ("c/d.txt", ""),
+        ]);
+        let opts = ScanOptions::default();
+        let walked: Vec<PathBuf> = walk(dir.path(), &opts).map(|e| e.unwrap().path).collect();
+
+        let mut built = Vec::new();
+        flatten(&tree(dir.path(), &[]), &mut built);
+        assert_eq!(walked.len(), 7);
+        assert_eq!(walked, built);
+    }
+
+    #[test]
+    fn visitor_sees_each_entry_once_at_its_depth() {
+        let dir = fixture(&[
+            ("a/b/c.txt", ""),
+            ("a/d.txt", ""),
+            ("e.txt", ""),
+            ("skip/f.txt", ""),
+        ]);
+        let mut seen = Vec::new();
+        scan_with(dir.path(), &ScanOptions::default(), |entry, depth| {
+            let rel = entry.path.strip_prefix(dir.path()).unwrap();
+            seen.push((rel.to_string_lossy().replace('\\', "/"), depth));
+            if entry.name == "skip" {
+                Visit::SkipSubtree
+            } else {
+                Visit::Continue
+            }
+        })
+        .unwrap();
+
+        let expected = [
+            ("a", 1),
+            ("a/b", 2),
+            ("a/b/c.txt", 3),
+            ("a/d.txt", 2),
+            ("e.txt", 1),
+            ("skip", 1),
+        ];
+        let expected: Vec<_> = expected.iter().map(|&(p, d)| (p.to_string(), d)).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn options_built_in_code_drive_a_scan() {
+        let dir = fixture(&[
+            ("big.rs", "0123456789"),
+            ("small.rs", "0"),
+            ("notes.md", ""),
+            ("deep/x/y.rs", ""),
+        ]);
+        let opts = ScanOptions::default()
+            .extensions(["rs"])
+            .unwrap()
+            .reverse(true)
+            .max_depth(Some(1));
+
+        let names: Vec<String> = walk(dir.path(), &opts).map(|e| e.unwrap().name).collect();
+        assert_eq!(names, ["small.rs", "deep", "big.rs"]);
+
+        let _output = lock_output(false);
+        let mut out = Vec::new();
+        run_with_options(dir.path(), &opts, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(listed(&out), ["small.rs", "deep", "big.rs"]);
+    }
+
+    #[test]
+    fn glob_star_matches_by_extension() {
+        let glob = GlobPattern::new("*.rs").unwrap();
+        assert!(glob.matches("main.rs", Path::new("src/main.rs")));
+        assert!(!glob.matches("main.rsx", Path::new("src/main.rsx")));
+        assert!(!glob.matches("mainrs", Path::new("src/mainrs")));
+
+        let dir = fixture(&[("src/main.rs", ""), ("src/main.rsx", ""), ("README.md", "")]);
+        let out = run_ok(dir.path(), &["--glob", "*.rs"]);
+        assert_eq!(listed(&out), ["src", "main.rs"]);
+    }
+
+    #[test]
+    fn changed_lists_only_files_modified_since_head() {
+        let dir = fixture(&[("a.txt", "one"), ("b.txt", "two"), ("src/c.rs", "three")]);
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "-q", "-m", "initial"]);
+        fs::write(dir.path().join("src/c.rs"), "changed").unwrap();
+
+        let out = run_ok(dir.path(), &["--changed"]);
+        assert_eq!(listed(&out), ["src", "c.rs"]);
+    }
+
+    #[test]
+    fn timeout_stops_a_slow_scan_part_way() {
+        let files: Vec<(String, &str)> = (0..5)
+            .flat_map(|d| (0..2).map(move |f| (format!("d{d}/f{f}.txt"), "")))
+            .collect();
+        let files: Vec<(&str, &str)> = files.iter().map(|(p, c)| (p.as_str(), *c)).collect();
+        let dir = fixture(&files);
+
+        // every entry takes 20ms to visit, so the whole scan would need 300ms
+        let opts = ScanOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..ScanOptions::default()
+        };
+        let mut seen = 0;
+        scan_with(dir.path(), &opts, |_, _| {
+            std::thread::sleep(Duration::from_millis(20));
+            seen += 1;
+            Visit::Continue
+        })
+        .unwrap();
+        assert!(seen > 0 && seen < 15, "visited {seen} entries");
+
+        let out = run_ok(dir.path(), &["--timeout", "0ms"]);
+        assert!(out.contains("0 directories, 0 files"), "{out}");
+    }
+
+    #[test]
+    fn overview_prints_one_row_per_directory() {
+        let dir = fixture(&[
+            ("a.txt", "abc"),
+            ("src/b.rs", "fn b(){"),
+            ("src/c.rs", ""),
+            ("src/sub/d.rs", "//"),
+        ]);
+        let out = run_ok(dir.path(), &["--overview"]);
+        let rows: Vec<Vec<&str>> = out
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                [".", "1", "files", "12.0", "B"],
+                ["src", "2", "files", "9.0", "B"],
+                ["src/sub", "1", "files", "2.0", "B"],
+            ]
+        );
+    }
+
+    #[test]
+    fn no_hidden_dirs_keeps_hidden_files() {
+        let dir = fixture(&[
+            (".env", ""),
+            (".git/config", ""),
+            ("a.txt", ""),
+            ("src/.keep", ""),
+        ]);
+        let out = run_ok(dir.path(), &["-a", "--no-hidden-dirs"]);
+        assert_eq!(listed(&out), [".env", "a.txt", "src", ".keep"]);
+        let out = run_ok(dir.path(), &["-a"]);
+        assert_eq!(
+            listed(&out),
+            [".env", ".git", "config", "a.txt", "src", ".keep"]
+        );
+    }
+
+    #[test]
+    fn aligned_sizes_share_one_column() {
+        let dir = fixture(&[("tiny", "x"), ("mid", &"x".repeat(2048))]);
+        fs::File::create(dir.path().join("big"))
+            .unwrap()
+            .set_len(3 << 20)
+            .unwrap();
+        let out = run_ok(dir.path(), &["-l", "--align"]);
+        let date = Regex::new(r"\d{4}-\d\d-\d\d").unwrap();
+        let rows: Vec<&str> = out.lines().take_while(|l| !l.is_empty()).collect();
+        let columns: Vec<usize> = rows
+            .iter()
+            .map(|row| row[..date.find(row).unwrap().start()].chars().count())
+            .collect();
+        assert_eq!(rows.len(), 4);
+        assert!(columns.iter().all(|&c| c == columns[0]), "{out}");
+        assert!(rows
+            .iter()
+            .any(|row| row.trim_start().starts_with("3.0 MB")));
+    }
+
+    #[test]
+    fn flat_json_rows_point_at_their_parents() {
+        let dir = fixture(&[("a.txt", ""), ("src/lib.rs", ""), ("src/bin/main.rs", "")]);
+        let json = json_export(dir.path(), &["--json-flat"]);
+        let rows = json.as_array().unwrap();
+        // root, a.txt, src, lib.rs, bin, main.rs
+        assert_eq!(rows.len(), 6);
+        assert!(rows[0]["parent"].is_null());
+        for (i, row) in rows.iter().enumerate().skip(1) {
+            let parent = row["parent"].as_u64().unwrap() as usize;
+            assert!(parent < i, "row {i} names a later parent");
+            assert_eq!(rows[parent]["is_dir"], true);
+            assert_eq!(row["depth"], rows[parent]["depth"].as_u64().unwrap() + 1);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_rows_match_the_tree() {
+        let dir = fixture(&[("a.txt", "hello"), ("src/lib.rs", "")]);
+        let root = tree(dir.path(), &[]);
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(insert_entries(&mut conn, &root).unwrap(), 4);
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 4);
+        let (path, parent, depth, size, is_dir): (String, i64, i64, i64, bool) = conn
+            .query_row(
+                "SELECT path, parent, depth, size, is_dir FROM entries WHERE name = 'a.txt'",
+                [],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(Path::new(&path), dir.path().join("a.txt"));
+        assert_eq!((parent, depth, size, is_dir), (0, 1, 5, false));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_keeps_an_existing_entries_table() {
+        let dir = fixture(&[("a.txt", "hello")]);
+        let dest = tempfile::tempdir().unwrap();
+        let db = dest.path().join("mine.db");
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE entries (note TEXT); INSERT INTO entries VALUES ('keep');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let (_, result) = try_run(dir.path(), &["-q", "--sqlite", db.to_str().unwrap()]);
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string().contains("already has an entries table"),
+            "{err}"
+        );
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        let note: String = conn
+            .query_row("SELECT note FROM entries", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(note, "keep");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_leaves_unless_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = fixture(&[("real/f.txt", "")]);
+        symlink("real", dir.path().join("link")).unwrap();
+        let out = run_ok(dir.path(), &[]);
+        assert_eq!(listed(&out), ["link@ -> real", "real", "f.txt"]);
+
+        let out = run_ok(dir.path(), &["--follow-symlinks"]);
+        assert_eq!(listed(&out), ["link@ -> real", "f.txt", "real", "f.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn following_does_not_depend_on_what_was_listed_first() {
+        use std::os::unix::fs::symlink;
+
+        let dir = fixture(&[("real/f.txt", "")]);
+        symlink("real", dir.path().join("a_link")).unwrap();
+        symlink("real", dir.path().join("z_link")).unwrap();
+        let out = run_ok(dir.path(), &["--follow-symlinks"]);
+        assert_eq!(
+            listed(&out),
+            [
+                "a_link@ -> real",
+                "f.txt",
+                "real",
+                "f.txt",
+                "z_link@ -> real",
+                "f.txt"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_to_an_enclosing_directory_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = fixture(&[("real/f.txt", "")]);
+        symlink("..", dir.path().join("real/up")).unwrap();
+        symlink(".", dir.path().join("real/here")).unwrap();
+        let out = run_ok(dir.path(), &["--follow-symlinks"]);
+        assert_eq!(listed(&out), ["real", "f.txt", "here@ -> .", "up@ -> .."]);
+    }
+
+    #[test]
+    fn compact_uses_two_column_indentation() {
+        let dir = fixture(&[("a/b.txt", ""), ("c.txt", "")]);
+        let out = run_ok(dir.path(), &["--compact", "--root-label", "r"]);
+        let rows: Vec<&str> = out.lines().take_while(|l| !l.is_empty()).collect();
+        assert_eq!(rows, ["r", "│ ├ a", "│   └ b.txt", "  └ c.txt"]);
+        assert_eq!(
+            out,
+            run_ok(dir.path(), &["--indent", "2", "--root-label", "r"])
+        );
+    }
+
+    #[test]
+    fn compact_is_overridden_only_by_later_flags() {
+        let dir = fixture(&[("a/b.txt", "xy")]);
+        let run = |flags: &[&str]| run_ok(dir.path(), &[&["--root-label", "r"], flags].concat());
+        let compact = run(&["--compact"]);
+
+        assert_eq!(run(&["-l", "--size-inline", "--compact"]), compact);
+        assert_eq!(run(&["--indent", "4", "--compact"]), compact);
+        assert_eq!(
+            run(&["--compact", "--indent", "4"]),
+            run(&["--indent", "4"])
+        );
+        let inline = run(&["--compact", "--size-inline"]);
+        assert_eq!(listed(&inline), ["a (2.0 B)", "b.txt (2.0 B)"]);
+
+        // the stats line follows the 2-column indentation
+        let long = run(&["--compact", "-l"]);
+        let rows: Vec<&str> = long.lines().take_while(|l| !l.is_empty()).collect();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[2], "  └ a");
+        assert!(rows[3].starts_with("    Size:"), "{long}");
+        assert!(rows[5].starts_with("      Size:"), "{long}");
+    }
+
+    #[test]
+    fn indent_builder_rejects_what_the_cli_rejects() {
+        assert!(ScanOptions::default().indent(2).is_ok());
+        for width in [1, 9] {
+            assert!(parse(Path::new("."), &["--indent", &width.to_string()])
+                .map(create_scan_options_from_args)
+                .unwrap()
+                .is_err());
+            let Err(ParseError::Args(err)) = ScanOptions::default().indent(width) else {
+                panic!("indent {width} was accepted");
+            };
+            assert!(matches!(err.details, ArgParseErrorType::BadIndent(w) if w == width));
+        }
+    }
+
+    #[test]
+    fn summary_json_matches_the_counted_totals() {
+        let dir = fixture(&[("a.txt", "abc"), ("src/b.rs", "abcd"), ("src/sub/c", "")]);
+        let totals = summary(dir.path(), &[]);
+        assert_eq!(
+            totals,
+            serde_json::json!({"directories": 2, "files": 3, "bytes": 7})
+        );
+
+        let out = run_ok(dir.path(), &["--summary-json"]);
+        assert!(
+            out.contains("\n2 directories, 3 files, 7.0 B  bytes total\n"),
+            "{out}"
+        );
+        let last: serde_json::Value = serde_json::from_str(out.lines().last().unwrap()).unwrap();
+        assert_eq!(last, totals);
+    }
+
+    #[test]
+    fn extension_case_matters_only_with_the_flag() {
+        let dir = fixture(&[("a.C", ""), ("b.c", ""), ("d.h", "")]);
+        let out = run_ok(dir.path(), &["-e", "c"]);
+        assert_eq!(listed(&out), ["a.C", "b.c"]);
+        let out = run_ok(dir.path(), &["-e", "c", "--case-sensitive-ext"]);
+        assert_eq!(listed(&out), ["b.c"]);
+        let out = run_ok(dir.path(), &["-e", "C", "--case-sensitive-ext"]);
+        assert_eq!(listed(&out), ["a.C"]);
+    }
+
+    #[test]
+    fn context_shows_dimmed_neighbours_of_matches() {
+        let dir = fixture(&[
+            ("a.txt", ""),
+            ("b.txt", ""),
+            ("c.rs", ""),
+            ("d.txt", ""),
+            ("e.txt", ""),
+        ]);
+        let out = run_ok(dir.path(), &["-e", "rs", "--context", "1"]);
+        assert_eq!(listed(&out), ["b.txt", "c.rs", "d.txt"]);
+        assert!(out.contains("\n0 directories, 1 files"), "{out}");
+
+        let out = run_colored(dir.path(), &["-e", "rs", "--context", "1"]);
+        assert!(out.contains("\x1b[2mb.txt\x1b[0m"), "{out:?}");
+        assert!(out.contains("\x1b[2md.txt\x1b[0m"), "{out:?}");
+        assert!(out.contains("\x1b[1;31mc.rs\x1b[0m"), "{out:?}");
+    }
+
+    #[test]
+    fn exclude_larger_than_leaves_huge_files_out_of_the_total() {
+        let dir = fixture(&[("small.txt", "hello")]);
+        fs::File::create(dir.path().join("huge.bin"))
+            .unwrap()
+            .set_len(20 << 20)
+            .unwrap();
+        assert_eq!(summary(dir.path(), &[])["bytes"], (20 << 20) + 5);
+        let totals = summary(dir.path(), &["--exclude-larger-than", "10M"]);
+        assert_eq!(totals["bytes"], 5);
+        assert_eq!(totals["files"], 1);
+    }
+
+    #[test]
+    fn outline_indents_without_glyphs() {
+        let dir = fixture(&[("a/b/c.txt", ""), ("a/d.txt", ""), ("e.txt", "")]);
+        let out = run_ok(dir.path(), &["--outline", "--root-label", "r"]);
+        let expected = [
+            "r",
+            "    a/",
+            "        b/",
+            "            c.txt",
+            "        d.txt",
+            "    e.txt",
+            "",
+            "2 directories, 3 files, 0.0 B  bytes total",
+        ];
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn since_lists_files_changed_after_a_ref() {
+        let dir = fixture(&[("a.txt", "one"), ("b.txt", "two")]);
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "-q", "-m", "first"]);
+        git(dir.path(), &["tag", "first"]);
+        fs::write(dir.path().join("b.txt"), "changed").unwrap();
+        fs::write(dir.path().join("c.txt"), "new").unwrap();
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "-q", "-m", "second"]);
+
+        let out = run_ok(dir.path(), &["--since", "first"]);
+        assert_eq!(listed(&out), ["b.txt", "c.txt"]);
+        let out = run_ok(dir.path(), &["--since", "HEAD"]);
+        assert!(listed(&out).is_empty(), "{out}");
+    }
+
+    #[test]
+    fn heat_colors_files_by_age_bucket() {
+        let dir = fixture(&[("new.txt", ""), ("old.txt", "")]);
+        let year_ago = SystemTime::now() - Duration::from_secs(400 * 86400);
+        fs::File::options()
+            .write(true)
+            .open(dir.path().join("old.txt"))
+            .unwrap()
+            .set_modified(year_ago)
+            .unwrap();
+
+        let out = run_colored(dir.path(), &["--heat"]);
+        assert_ne!(style_of(&out, "new.txt"), "");
+        assert_ne!(style_of(&out, "new.txt"), style_of(&out, "old.txt"));
+    }
+
+    #[test]
+    fn size_heat_styles_large_and_small_files_apart() {
+        let dir = fixture(&[("small.txt", "x")]);
+        fs::File::create(dir.path().join("large.bin"))
+            .unwrap()
+            .set_len(200 << 20)
+            .unwrap();
+        let out = run_colored(dir.path(), &["--size-heat"]);
+        assert_eq!(style_of(&out, "small.txt"), "\x1b[2m");
+        assert_ne!(style_of(&out, "large.bin"), "");
+        assert_ne!(style_of(&out, "large.bin"), style_of(&out, "small.txt"));
+    }
+
+    #[test]
+    fn annotate_records_the_filters_used() {
+        let dir = fixture(&[("a.txt", "")]);
+        let flags = ["--annotate", "-e", "txt", "--max-depth", "2"];
+        let out = run_ok(dir.path(), &flags);
+        let header = out.lines().next().unwrap();
+        let expected = format!(
+            "# mytree {} --extension txt --max-depth 2 at ",
+            dir.path().display()
+        );
+        assert!(header.starts_with(&expected), "{header}");
+
+        let json = json_export(dir.path(), &flags);
+        assert_eq!(
+            json["meta"]["options"],
+            serde_json::json!(["--extension txt", "--max-depth 2"])
+        );
+        assert_eq!(json["tree"][0]["children"][0]["name"], "a.txt");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directories_are_reported_after_the_rest() {
+        let dir = fixture(&[("ok.txt", ""), ("deep/inside.txt", "")]);
+        // a chain of directories nested past PATH_MAX, which even root cannot
+        // read by its full path: built as two halves, then one moved into the other
+        let half: PathBuf = std::iter::repeat_n("n".repeat(250), 10).collect();
+        let (upper, lower) = (dir.path().join("deep").join(&half), dir.path().join(&half));
+        fs::create_dir_all(&upper).unwrap();
+        fs::create_dir_all(&lower).unwrap();
+        let top = dir.path().join("n".repeat(250));
+        fs::rename(&top, upper.join("n".repeat(250))).unwrap();
+
+        let opts = ScanOptions::default();
+        let mut state = WalkState::new(&opts);
+        state.collect_errors = true;
+        let root = build_directory_tree(dir.path(), &opts, &mut state).unwrap();
+        assert_eq!(state.errors.len(), 1, "{:?}", state.errors);
+        let names: Vec<_> = root
+            .children
+            .iter()
+            .flatten()
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(names, ["deep", "ok.txt"]);
+
+        // without collecting, the same error ends the walk
+        let mut state = WalkState::new(&opts);
+        assert!(build_directory_tree(dir.path(), &opts, &mut state).is_err());
+    }
+
+    #[test]
+    fn long_names_are_cut_to_the_limit_with_an_ellipsis() {
+        let dir = fixture(&[("abcdefghijklmnop.txt", ""), ("short", "")]);
+        let out = run_ok(dir.path(), &["--max-name-length", "8"]);
+        assert_eq!(listed(&out), ["abcdefg…", "short"]);
+        assert_eq!(listed(&out)[0].chars().count(), 8);
+    }
+
+    #[test]
+    fn deep_children_get_relative_json_paths() {
+        let dir = fixture(&[("a/b/c/d.txt", "")]);
+        let json = json_export(
+            dir.path(),
+            &["--json-relative-paths", "--json-children-only"],
+        );
+        let d = &json[0]["children"][0]["children"][0]["children"][0];
+        assert_eq!(d["name"], "d.txt");
+        assert_eq!(d["path"], "a/b/c/d.txt");
+    }
+
+    #[test]
+    fn mark_empty_flags_only_empty_directories() {
+        let dir = fixture(&[("empty/", ""), ("full/a.txt", "")]);
+        let out = run_ok(dir.path(), &["--mark-empty"]);
+        assert_eq!(listed(&out), ["empty (empty)", "full", "a.txt"]);
+        let out = run_ok(dir.path(), &[]);
+        assert_eq!(listed(&out), ["full", "a.txt"]);
+    }
+
+    #[test]
+    fn no_recursion_lists_direct_children_only() {
+        let dir = fixture(&[("a.txt", ""), ("src/lib.rs", ""), ("src/bin/main.rs", "")]);
+        let out = run_ok(dir.path(), &["--no-recursion"]);
+        assert_eq!(listed(&out), ["a.txt", "src"]);
+        assert_eq!(out, run_ok(dir.path(), &["-1"]));
+    }
+
+    #[test]
+    fn mytree_root_is_used_when_no_path_is_given() {
+        let dir = fixture(&[("inside.txt", "")]);
+        let root = default_root(Some(dir.path().into()));
+        assert_eq!(root, dir.path());
+        assert_eq!(default_root(Some(OsString::new())), Path::new("."));
+        assert_eq!(default_root(None), Path::new("."));
+    }
+
+    #[test]
+    fn category_summary_counts_files_by_kind() {
+        let dir = fixture(&[
+            ("src/a.rs", ""),
+            ("src/b.py", ""),
+            ("README.md", ""),
+            ("logo.png", ""),
+            ("Makefile", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--category-summary"]);
+        assert!(
+            out.lines()
+                .any(|l| l == "code: 2, docs: 1, images: 1, other: 1"),
+            "{out}"
+        );
+        assert_eq!(category_line(&[0; 7]), "no files");
+    }
+
+    #[test]
+    fn sample_keeps_the_first_children_without_an_elision_line() {
+        let dir = fixture(&[
+            ("a/1", ""),
+            ("a/2", ""),
+            ("a/3", ""),
+            ("b/x", ""),
+            ("b/y", ""),
+            ("b/z", ""),
+            ("r1", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--sample", "2"]);
+        assert_eq!(listed(&out), ["a", "1", "2", "b", "x", "y"]);
+        assert!(!out.contains("more"), "{out}");
+    }
+
+    #[test]
+    fn repo_root_scans_from_the_enclosing_repository() {
+        let dir = fixture(&[(".git/", ""), ("src/deep/nested/", ""), ("top.txt", "")]);
+        let (root, _) = options(&dir.path().join("src/deep/nested"), &["--repo-root"]);
+        assert_eq!(root, fs::canonicalize(dir.path()).unwrap());
+
+        let bare = fixture(&[("loose/", "")]);
+        let outside_any_repo = fs::canonicalize(bare.path())
+            .unwrap()
+            .ancestors()
+            .all(|dir| !dir.join(".git").exists());
+        if outside_any_repo {
+            let args = parse(&bare.path().join("loose"), &["--repo-root"]).unwrap();
+            assert!(create_scan_options_from_args(args).is_err());
+        }
+    }
+
+    #[test]
+    fn dir_and_file_prefixes_mark_each_kind() {
+        let dir = fixture(&[("src/lib.rs", ""), ("notes.txt", "")]);
+        let out = run_ok(dir.path(), &["--dir-prefix", "▸ ", "--file-prefix", "· "]);
+        assert_eq!(listed(&out), ["· notes.txt", "▸ src", "· lib.rs"]);
+    }
+
+    #[test]
+    fn bottom_up_lists_children_above_their_parent() {
+        let dir = fixture(&[("a/1", ""), ("a/2", ""), ("b", "")]);
+        let top_down = run_ok(dir.path(), &[]);
+        let bottom_up = run_ok(dir.path(), &["--bottom-up"]);
+        let root = dir.path().display();
+        assert_eq!(
+            top_down,
+            format!(
+                "{root}\n│   ├── a\n│   │   ├── 1\n│       └── 2\n    └── b\n\n\
+                 1 directories, 3 files, 0.0 B  bytes total\n"
+            )
+        );
+        assert_eq!(
+            bottom_up,
+            format!(
+                "    ┌── b\n│       ┌── 2\n│   │   ├── 1\n│   ├── a\n{root}\n\n\
+                 1 directories, 3 files, 0.0 B  bytes total\n"
+            )
+        );
+    }
+
+    #[test]
+    fn json_stats_total_size_sums_file_descendants() {
+        let dir = fixture(&[
+            ("src/lib.rs", "12345"),
+            ("src/bin/main.rs", "123"),
+            ("src/bin/util.rs", "12"),
+            ("README.md", "1"),
+        ]);
+        let json = json_export(dir.path(), &["--json-stats"]);
+        let root = &json[0];
+        assert_eq!(root["total_size"], 11);
+        assert_eq!(root["file_count"], 4);
+        let src = root["children"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|child| child["name"] == "src")
+            .unwrap();
+        assert_eq!(src["total_size"], 10);
+        assert_eq!(src["file_count"], 3);
+    }
+
+    #[test]
+    fn fail_on_empty_errors_when_nothing_matches() {
+        let dir = fixture(&[("a.rs", ""), ("sub/b.txt", "")]);
+        let (_, result) = try_run(dir.path(), &["-e", "nothing", "--fail-on-empty"]);
+        assert!(result.is_err());
+        let (_, result) = try_run(dir.path(), &["-e", "rs", "--fail-on-empty"]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn depth_colors_tint_each_level_differently() {
+        let dir = fixture(&[("one", ""), ("two", ""), ("d1/f2", ""), ("d1/d2/f3", "")]);
+        let out = run_colored(dir.path(), &["--depth-colors"]);
+        let (one, two) = (style_of(&out, "one"), style_of(&out, "two"));
+        let (f2, f3) = (style_of(&out, "f2"), style_of(&out, "f3"));
+        assert!(!one.is_empty(), "{out}");
+        assert_eq!(one, two);
+        assert_ne!(one, f2);
+        assert_ne!(f2, f3);
+        assert_ne!(one, f3);
+    }
+
+    #[test]
+    fn json_indent_sets_the_indentation_width() {
+        let dir = fixture(&[("a.txt", "")]);
+        let dest = tempfile::tempdir().unwrap();
+        let file = dest.path().join("tree.json");
+        let file_arg = file.to_str().unwrap();
+        run_ok(
+            dir.path(),
+            &["-q", "--json-indent", "4", "--json", file_arg],
+        );
+        let text = fs::read_to_string(&file).unwrap();
+        assert!(text.starts_with("[\n    {\n        \"name\""), "{text}");
+
+        run_ok(
+            dir.path(),
+            &["-q", "--json-indent", "0", "--json", file_arg],
+        );
+        assert!(!fs::read_to_string(&file).unwrap().contains('\n'));
+    }
+
+    #[test]
+    fn perms_with_long_describe_the_root_line() {
+        let dir = fixture(&[("a.txt", "")]);
+        let out = run_ok(dir.path(), &["--perms", "--long"]);
+        let root = out.lines().next().unwrap();
+        assert!(root.starts_with("[d"), "{root}");
+        assert!(
+            root.ends_with(&format!("] {}", dir.path().display())),
+            "{root}"
+        );
+        assert!(out.lines().nth(1).unwrap().contains("Size:"), "{out}");
+    }
+
+    #[test]
+    fn count_prints_only_the_number_of_matching_files() {
+        let dir = fixture(&[
+            ("src/lib.rs", ""),
+            ("src/bin/main.rs", ""),
+            ("build.rs", ""),
+            ("README.md", ""),
+        ]);
+        assert_eq!(run_ok(dir.path(), &["--count", "rs"]), "3\n");
+        assert_eq!(
+            run_ok(dir.path(), &["--count", "rs", "--count", ".md"]),
+            "3\n1\n"
+        );
+    }
+
+    #[test]
+    fn prune_dir_drops_matching_directories_but_not_files() {
+        let dir = fixture(&[
+            ("target/debug/app", ""),
+            ("src/target", ""),
+            ("src/main.rs", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--prune-dir", "target"]);
+        assert_eq!(listed(&out), ["src", "main.rs", "target"]);
+    }
+
+    #[test]
+    fn size_sort_places_directories_by_their_aggregate_size() {
+        let chunk = "x".repeat(4000);
+        let dir = fixture(&[
+            ("big/a", &chunk),
+            ("big/b", &chunk),
+            ("mid", &"x".repeat(5000)),
+            ("small", "x"),
+        ]);
+        let out = run_ok(dir.path(), &["--sort", "fs"]);
+        assert_eq!(listed(&out)[..3], ["small", "mid", "big"]);
+    }
+
+    #[test]
+    fn manifest_is_sha256sum_compatible() {
+        // FIPS 180-2 vectors: the empty message, "abc" and a 112-byte message
+        let long = "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                    hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+        let dir = fixture(&[("abc.txt", "abc"), ("empty", ""), ("sub/long.txt", long)]);
+        let dest = tempfile::tempdir().unwrap();
+        let file = dest.path().join("tree.sha256");
+        run_ok(dir.path(), &["-q", "--manifest", file.to_str().unwrap()]);
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  abc.txt\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  empty\n\
+             cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1  sub/long.txt\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn manifest_skips_everything_but_regular_files() {
+        let dir = fixture(&[("a.txt", "abc"), ("sub/b.txt", "")]);
+        std::os::unix::fs::symlink("sub", dir.path().join("linkdir")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.path().join("dangling")).unwrap();
+        make_fifo(&dir.path().join("pipe"));
+        let dest = tempfile::tempdir().unwrap();
+        let file = dest.path().join("tree.sha256");
+        run_ok(dir.path(), &["-q", "--manifest", file.to_str().unwrap()]);
+
+        let manifest = fs::read_to_string(&file).unwrap();
+        let names: Vec<&str> = manifest
+            .lines()
+            .map(|l| l.split_once("  ").unwrap().1)
+            .collect();
+        assert_eq!(names, ["a.txt", "sub/b.txt"]);
+        let status = std::process::Command::new("sha256sum")
+            .args(["-c", "--quiet"])
+            .arg(&file)
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn child_count_shows_a_directory_breadth() {
+        let dir = fixture(&[("src/a.rs", ""), ("src/b.rs", ""), ("src/bin/main.rs", "")]);
+        let out = run_ok(dir.path(), &["--child-count"]);
+        assert!(out.lines().any(|l| l.ends_with("src [3]")), "{out}");
+        assert!(out.lines().any(|l| l.ends_with("bin [1]")), "{out}");
+        assert!(out.lines().any(|l| l.ends_with("a.rs")), "{out}");
+    }
+
+    #[test]
+    fn running_totals_climb_to_the_summary_size() {
+        let dir = fixture(&[("a/1", "xxxxx"), ("a/2", ""), ("b", "xxx"), ("c/d/e", "xx")]);
+        let out = run_ok(dir.path(), &["--running-total"]);
+        let totals: Vec<&str> = out
+            .lines()
+            .filter_map(|line| line.split_once("(total ")?.1.strip_suffix(')'))
+            .collect();
+        assert_eq!(totals, ["5.0 B", "5.0 B", "8.0 B", "10.0 B"]);
+        assert_eq!(summary(dir.path(), &[])["bytes"], 10);
+    }
+
+    #[test]
+    fn svg_falls_back_to_a_dot_file_without_graphviz() {
+        let dir = fixture(&[("src/lib.rs", "")]);
+        let node = tree(dir.path(), &[]);
+        let dest = tempfile::tempdir().unwrap();
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        let dot = tree_dot(&node);
+        let (written, _) = write_rendered_svg(
+            dest.path().join("tree.svg"),
+            dot.clone(),
+            Err(missing),
+            false,
+        )
+        .unwrap();
+        assert_eq!(written, dest.path().join("tree.dot"));
+        assert_eq!(fs::read_to_string(&written).unwrap(), dot);
+        assert!(!dest.path().join("tree.svg").exists());
+
+        let failed = io::Error::other("dot failed: syntax error");
+        assert!(write_rendered_svg(dest.path().join("tree.svg"), dot, Err(failed), false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn raw_names_keep_non_utf8_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = fixture(&[("plain.txt", "")]);
+        let raw = b"caf\xe9.txt";
+        fs::write(dir.path().join(OsStr::from_bytes(raw)), "").unwrap();
+        let json = json_export(dir.path(), &["--json-raw-names"]);
+        let children = json[0]["children"].as_array().unwrap();
+        let odd = children
+            .iter()
+            .find(|child| child["name"] == "caf\u{fffd}.txt")
+            .unwrap();
+        let bytes: Vec<u8> = serde_json::from_value(odd["name_bytes"].clone()).unwrap();
+        assert_eq!(bytes, raw);
+        let plain = children.iter().find(|c| c["name"] == "plain.txt").unwrap();
+        assert!(plain.get("name_bytes").is_none());
+    }
 
-    let mut meta_entries = Vec::new(); // allocate lazily
+    #[test]
+    fn exclude_vcs_skips_only_version_control_directories() {
+        let dir = fixture(&[
+            (".git/HEAD", ""),
+            (".svn/entries", ""),
+            (".github/ci.yml", ""),
+            ("src/.hg/store", ""),
+            ("src/lib.rs", ""),
+            ("main.rs", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--all", "--exclude-vcs"]);
+        assert_eq!(
+            listed(&out),
+            [".github", "ci.yml", "main.rs", "src", "lib.rs"]
+        );
+    }
 
-    for dir_entry in iter {
-        let entry = dir_entry.map_err(|e| {
-            ParseError::Tree(TreeParseError {
-                details: TreeParseType::Io(format!(
-                    "error reading an entry in {}: {e}",
-                    path.display()
-                )),
-            })
-        })?;
+    #[test]
+    fn summary_for_matches_the_subtree_scanned_alone() {
+        let dir = fixture(&[
+            ("src/lib.rs", "12345"),
+            ("src/bin/main.rs", "12"),
+            ("docs/guide.md", "123456789"),
+        ]);
+        let src = dir.path().join("src");
+        let whole = run_ok(dir.path(), &["--summary-for", "src"]);
+        let alone = run_ok(&src, &[]);
+        let expected = format!("{}: {}", src.display(), alone.lines().last().unwrap());
+        assert_eq!(whole.lines().last().unwrap(), expected);
+        assert!(listed(&whole).contains(&"guide.md"));
 
-        let file_type = entry.file_type().map_err(|e| {
-            ParseError::Tree(TreeParseError {
-                details: TreeParseType::InvalidInput(format!(
-                    "could not determine file type for {}: {e}",
-                    entry.path().display()
-                )),
-            })
-        })?;
+        let outside = parse(&src, &["--summary-for", "../docs"]).unwrap();
+        assert!(create_scan_options_from_args(outside).is_err());
+    }
 
-        let name = entry.file_name().to_string_lossy().to_string();
-        let is_dir = file_type.is_dir();
-        let ext = entry
-            .path()
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("")
-            .to_ascii_lowercase();
+    #[test]
+    fn shuffle_order_is_fixed_by_the_seed() {
+        let names: Vec<String> = (0..12).map(|i| format!("f{i:02}")).collect();
+        let entries: Vec<(&str, &str)> = names.iter().map(|n| (n.as_str(), "")).collect();
+        let dir = fixture(&entries);
+        let first = run_ok(dir.path(), &["--shuffle", "--seed", "1"]);
+        let again = run_ok(dir.path(), &["--shuffle", "--seed", "1"]);
+        let other = run_ok(dir.path(), &["--shuffle", "--seed", "2"]);
+        assert_eq!(listed(&first), listed(&again));
+        assert_ne!(listed(&first), listed(&other));
+        let mut sorted = listed(&first);
+        sorted.sort();
+        assert_eq!(sorted, names);
+    }
 
-        if !opts.show_hidden && name.starts_with('.') {
-            continue;
+    #[test]
+    fn newest_and_oldest_pick_by_modification_time() {
+        let dir = fixture(&[("a", ""), ("b", ""), ("sub/c", ""), ("sub/d", "")]);
+        let now = SystemTime::now();
+        for (days, name) in [(4, "a"), (1, "sub/c"), (3, "b"), (2, "sub/d")] {
+            fs::File::options()
+                .write(true)
+                .open(dir.path().join(name))
+                .unwrap()
+                .set_modified(now - Duration::from_secs(days * 86400))
+                .unwrap();
         }
-        if !is_dir {
-            if opts
-                .extension_filters
-                .as_ref()
-                .is_some_and(|set| !set.contains(ext.as_str()))
-            {
-                continue;
-            }
-            if opts
-                .regex_filter
-                .as_ref()
-                .is_some_and(|re| !re.is_match(&name))
-            {
+        let newest = run_ok(dir.path(), &["--newest", "2"]);
+        assert_eq!(listed(&newest), ["sub", "c", "d"]);
+        let oldest = run_ok(dir.path(), &["--oldest", "1"]);
+        assert_eq!(listed(&oldest), ["a"]);
+    }
+
+    // a zip built in memory, written out as <dir>/bundle.zip
+    fn zip_fixture(members: &[(&str, &str)]) -> TempDir {
+        use zip::write::SimpleFileOptions;
+
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (i, (name, contents)) in members.iter().enumerate() {
+            if name.ends_with('/') {
+                writer
+                    .add_directory(*name, SimpleFileOptions::default())
+                    .unwrap();
                 continue;
             }
+            // alternate compression, and give one member zip64 size fields
+            let options = SimpleFileOptions::default()
+                .compression_method(if i % 2 == 0 {
+                    zip::CompressionMethod::Deflated
+                } else {
+                    zip::CompressionMethod::Stored
+                })
+                .large_file(i == 0);
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
         }
+        let bytes = writer.finish().unwrap().into_inner();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("bundle.zip"), bytes).unwrap();
+        dir
+    }
 
-        let md = entry.metadata().map_err(|e| {
-            ParseError::Tree(TreeParseError {
-                details: TreeParseType::Io(format!(
-                    "failed to read metadata for {}: {e}",
-                    entry.path().display()
-                )),
+    #[test]
+    fn zip_members_render_as_a_tree() {
+        let dir = zip_fixture(&[
+            ("src/main.rs", "fn main() {}\n"),
+            ("docs/", ""),
+            ("docs/guide.md", "# Guide\n"),
+            ("notes.txt", "a\nb\nc\n"),
+        ]);
+        let out = run_ok(&dir.path().join("bundle.zip"), &[]);
+        assert_eq!(
+            listed(&out),
+            ["docs", "guide.md", "notes.txt", "src", "main.rs"]
+        );
+        assert!(
+            out.ends_with("2 directories, 3 files, 27.0 B  bytes total\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn archive_members_go_through_the_walk_filters() {
+        let dir = zip_fixture(&[
+            ("deep/er/long.txt", "1\n2\n3\n"),
+            ("deep/er/short.txt", "1\n"),
+            ("lib/a.rs", ""),
+            ("lib/b.txt", ""),
+            ("lib/c.txt", ""),
+            ("lib/d.txt", ""),
+        ]);
+        let zip = dir.path().join("bundle.zip");
+        let out = run_ok(&zip, &["--min-lines", "2", "--collapse"]);
+        assert_eq!(listed(&out), ["deep/er", "long.txt"]);
+        let out = run_ok(&zip, &["-e", "rs", "--context", "1", "--prune-dir", "deep"]);
+        assert_eq!(listed(&out), ["lib", "a.rs", "b.txt"]);
+    }
+
+    #[test]
+    fn tar_members_keep_long_names_and_sizes() {
+        let long = format!("{}/file.txt", "d".repeat(120));
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in [(long.as_str(), "12345"), ("top.txt", "1")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mtime(1_000_000_000);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, name, contents.as_bytes())
+                .unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let tar = dir.path().join("bundle.tar");
+        fs::write(&tar, builder.into_inner().unwrap()).unwrap();
+
+        let root = tree(&tar, &[]);
+        let names: Vec<_> = root.children.iter().flatten().map(|n| &n.name).collect();
+        assert_eq!(names, [&"d".repeat(120), "top.txt"]);
+        let inner = &root.children.as_ref().unwrap()[0]
+            .children
+            .as_ref()
+            .unwrap()[0];
+        assert_eq!((inner.name.as_str(), inner.size), ("file.txt", 5));
+        assert_eq!(
+            inner.mtime,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn chart_bars_scale_with_the_counts() {
+        let dir = fixture(&[
+            ("1.rs", ""),
+            ("2.rs", ""),
+            ("3.rs", ""),
+            ("4.rs", ""),
+            ("a.md", ""),
+            ("b.md", ""),
+            ("x.txt", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--chart"]);
+        let bars: Vec<(usize, &str)> = out
+            .lines()
+            .filter(|line| line.contains('#'))
+            .map(|line| {
+                let count = line.split_whitespace().last().unwrap();
+                (line.matches('#').count(), count)
             })
-        })?;
+            .collect();
+        assert_eq!(bars.len(), 3, "{out}");
+        let (longest, _) = bars[0];
+        assert_eq!(bars[0].1, "4");
+        assert_eq!(bars[1], (longest / 2, "2"));
+        assert_eq!(bars[2], (longest / 4, "1"));
+    }
 
-        meta_entries.push(EntryMeta {
-            name,
-            path: entry.path(),
-            size: md.len(),
-            mtime: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
-            is_dir: file_type.is_dir(),
-        });
+    #[test]
+    fn breakdown_splits_top_level_from_nested() {
+        let dir = fixture(&[
+            ("a.txt", ""),
+            ("b.txt", ""),
+            ("src/lib.rs", ""),
+            ("src/bin/main.rs", ""),
+            ("src/bin/cli.rs", ""),
+        ]);
+        let line = "top-level: 1 directories, 2 files; nested: 1 directories, 3 files";
+        let out = run_ok(dir.path(), &["--breakdown"]);
+        assert!(out.lines().any(|l| l == line), "{out}");
+        let out = run_ok(dir.path(), &["--breakdown", "--collapse"]);
+        assert!(out.lines().any(|l| l == line), "{out}");
     }
 
-    Ok(sort_meta_entries(meta_entries, &opts.sort_by))
-}
+    #[test]
+    fn depth_weighting_ranks_a_buried_file_first() {
+        let dir = fixture(&[
+            ("a/b/c/deep.bin", &"x".repeat(300)),
+            ("big.bin", &"x".repeat(1000)),
+        ]);
+        let out = run_ok(dir.path(), &["--depth-weighted", "2"]);
+        let ranked: Vec<&str> = out
+            .lines()
+            .skip_while(|line| !line.trim_start().starts_with("weighted"))
+            .skip(1)
+            .map(|line| line.split_whitespace().last().unwrap())
+            .collect();
+        assert_eq!(ranked, ["a/b/c/deep.bin", "big.bin"]);
+    }
 
-fn sort_meta_entries(mut meta_entries: Vec<EntryMeta>, sort_criteria: &SortBy) -> Vec<EntryMeta> {
-    match sort_criteria {
-        SortBy::Alphabetical => {
-            meta_entries.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
-        }
-        SortBy::FileSize => {
-            meta_entries.sort_by(|a, b| a.size.cmp(&b.size));
-        }
-        SortBy::LastUpdatedTimestamp => {
-            meta_entries.sort_by(|a, b| b.mtime.cmp(&a.mtime));
-        }
+    #[test]
+    fn paths_only_prints_the_sorted_file_paths() {
+        let dir = fixture(&[
+            ("b.txt", ""),
+            ("src/z.rs", ""),
+            ("src/a.rs", ""),
+            ("A.md", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--paths-only"]);
+        let root = dir.path();
+        let mut expected: Vec<String> = ["A.md", "b.txt", "src/a.rs", "src/z.rs"]
+            .iter()
+            .map(|p| root.join(p).display().to_string())
+            .collect();
+        expected.sort();
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
     }
-    meta_entries
-}
 
-/*
-Return a vector of ordered row-level entries at a point in the directory
-*/
-fn build_directory_tree(root_path: &Path, opts: &PrintOptions) -> Result<TreeNode, ParseError> {
-    let md = fs::metadata(root_path).map_err(|e| {
-        ParseError::Tree(TreeParseError {
-            details: TreeParseType::Io(format!(
-                "failed to read metadata for {}: {e}",
-                root_path.display()
-            )),
-        })
-    })?;
+    #[cfg(unix)]
+    #[test]
+    fn follow_root_only_leaves_inner_links_unfollowed() {
+        use std::os::unix::fs::symlink;
 
-    let entries = create_ordered_row_level_entries(root_path, opts)?;
-    let mut kids = Vec::with_capacity(entries.len());
-    for entry in entries {
-        if let Some(node) = build_tree_node_from_entry_meta(entry, opts)? {
-            kids.push(node);
-        }
+        let dir = fixture(&[("real/file.txt", ""), ("other/hidden.txt", "")]);
+        symlink(dir.path().join("other"), dir.path().join("real/link")).unwrap();
+        symlink(dir.path().join("real"), dir.path().join("root")).unwrap();
+
+        let leaf = format!("link@ -> {}", dir.path().join("other").display());
+        let out = run_ok(&dir.path().join("root"), &["--follow-root-only"]);
+        assert_eq!(listed(&out), ["file.txt", leaf.as_str()]);
+        let out = run_ok(&dir.path().join("root"), &["--follow-symlinks"]);
+        assert_eq!(listed(&out)[2..], ["hidden.txt"]);
+        assert!(parse(dir.path(), &["--follow-root-only", "--follow-symlinks"]).is_err());
     }
 
-    Ok(TreeNode {
-        name: root_path
-            .file_name()
-            .map(|s| s.to_string_lossy().into_owned())
-            .unwrap_or_else(|| root_path.display().to_string()),
-        path: root_path.to_owned(),
-        size: md.len(),
-        mtime: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
-        is_dir: true,
-        children: Some(kids),
-    })
-}
+    #[test]
+    fn json_extension_is_lowercased_and_null_for_directories() {
+        let dir = fixture(&[("src/main.rs", ""), ("LOGO.PNG", ""), ("Makefile", "")]);
+        let json = json_export(dir.path(), &[]);
+        let children = json[0]["children"].as_array().unwrap();
+        let field = |name: &str| {
+            let node = children.iter().find(|c| c["name"] == name).unwrap();
+            node["extension"].clone()
+        };
+        assert_eq!(field("LOGO.PNG"), "png");
+        assert_eq!(field("Makefile"), serde_json::Value::Null);
+        assert_eq!(field("src"), serde_json::Value::Null);
+        assert_eq!(children[2]["children"][0]["extension"], "rs");
+    }
 
-fn build_tree_node_from_entry_meta(
-    entry: EntryMeta,
-    opts: &PrintOptions,
-) -> Result<Option<TreeNode>, ParseError> {
-    let children = if entry.is_dir {
-        let subs = create_ordered_row_level_entries(&entry.path, opts)?;
-        let mut nodes = Vec::with_capacity(subs.len());
-        for sub in subs {
-            if let Some(child) = build_tree_node_from_entry_meta(sub, opts)? {
-                nodes.push(child);
-            }
-        }
-        Some(nodes)
-    } else {
-        None
-    };
+    #[test]
+    fn size_inline_follows_each_name() {
+        let dir = fixture(&[("src/a.rs", &"x".repeat(2048)), ("b.txt", "abc")]);
+        let out = run_ok(dir.path(), &["--size-inline"]);
+        assert_eq!(
+            listed(&out),
+            ["b.txt (3.0 B)", "src (2.0 KB)", "a.rs (2.0 KB)"]
+        );
+        assert!(!out.contains("Modified:"), "{out}");
+    }
 
-    if entry.is_dir && matches!(children, Some(ref v) if v.is_empty()) {
-        return Ok(None);
+    #[test]
+    fn warn_depth_fires_only_past_the_limit() {
+        let dir = fixture(&[("a/b/c/deep.txt", ""), ("top.txt", "")]);
+        let warning = |flags: &[&str]| {
+            let (path, opts) = options(dir.path(), flags);
+            let mut state = WalkState::new(&opts);
+            build_directory_tree(&path, &opts, &mut state).unwrap();
+            depth_warning(state.deepest, &opts)
+        };
+        assert_eq!(
+            warning(&["--warn-depth", "2"]).unwrap(),
+            "mytree: the tree is 4 levels deep (more than 2); consider --max-depth 2 or a filter"
+        );
+        assert_eq!(warning(&["--warn-depth", "4"]), None);
+        assert_eq!(warning(&[]), None);
     }
 
-    Ok(Some(TreeNode {
-        name: entry.name,
-        path: entry.path,
-        size: entry.size,
-        mtime: entry.mtime,
-        is_dir: entry.is_dir,
-        children,
-    }))
-}
+    #[test]
+    fn size_expressions_cover_each_form() {
+        let range = |raw: &str| SizeRange::parse(raw).unwrap();
+        assert!(range("+1K").contains(1025) && !range("+1K").contains(1024));
+        assert!(range("-1K").contains(1023) && !range("-1K").contains(1024));
+        assert!(range("100..500").contains(100) && range("100..500").contains(500));
+        assert!(!range("100..500").contains(99) && !range("100..500").contains(501));
+        assert!(range("..10").contains(0) && !range("..10").contains(11));
+        assert!(range("10..").contains(u64::MAX) && !range("10..").contains(9));
+        assert!(range("7").contains(7) && !range("7").contains(8));
+        assert!(SizeRange::parse("-0").is_err());
+        assert!(SizeRange::parse("big").is_err());
 
-/*
-Print the directory tree to standard out or write to JSON
-*/
-fn print_tree(
-    node: &TreeNode,
-    connector: &str,
-    prefix: &str,
-    stats: &mut Stats,
-    opts: &PrintOptions,
-    w: &mut dyn FnMut(&str),
-) {
-    render_node(node, connector, prefix, opts, w);
-    accumulate(stats, node);
+        let dir = fixture(&[
+            ("tiny", "x"),
+            ("mid", &"x".repeat(300)),
+            ("large", &"x".repeat(2000)),
+        ]);
+        assert_eq!(listed(&run_ok(dir.path(), &["--size", "+1K"])), ["large"]);
+        assert_eq!(
+            listed(&run_ok(dir.path(), &["--size=-1K"])),
+            ["mid", "tiny"]
+        );
+        assert_eq!(
+            listed(&run_ok(dir.path(), &["--size", "100..500"])),
+            ["mid"]
+        );
+        assert_eq!(listed(&run_ok(dir.path(), &["--size", "1"])), ["tiny"]);
+    }
 
-    let Some(children) = node.children.as_ref() else {
-        return;
-    };
+    #[cfg(unix)]
+    #[test]
+    fn only_keeps_one_kind_of_entry() {
+        let dir = fixture(&[("empty", ""), ("full", "x"), ("sub/deeper/f", "y")]);
+        std::os::unix::fs::symlink("full", dir.path().join("link")).unwrap();
+        let only = |kind: &str| {
+            let out = run_ok(dir.path(), &["--only", kind]);
+            listed(&out)
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(only("file"), ["empty", "full", "sub", "deeper", "f"]);
+        assert_eq!(only("dir"), ["sub", "deeper"]);
+        assert_eq!(only("symlink"), ["link@ -> full"]);
+        assert_eq!(only("empty"), ["empty"]);
+        let socket = parse(dir.path(), &["--only", "socket"]).unwrap();
+        assert!(create_scan_options_from_args(socket).is_err());
+    }
 
-    let last = children.len().saturating_sub(1);
-    for (i, child) in children.iter().enumerate() {
-        let is_last = i == last;
-        let conn = if is_last { "└── " } else { "├── " };
-        let next_prefix = if is_last {
-            format!("{prefix}    ")
-        } else {
-            format!("{prefix}│   ")
+    #[test]
+    fn legend_styles_match_the_tree() {
+        let dir = fixture(&[("src/main.rs", ""), ("notes.md", "")]);
+        let out = run_colored(dir.path(), &["--legend"]);
+        let (tree, legend) = out.split_once("Legend:").unwrap();
+        for label in ["directory", "symlink", "hidden file"] {
+            assert!(legend.contains(label), "{legend}");
+        }
+        // the legend names, and paints, what the tree shows
+        let style_in = |text: &str, name: &str| {
+            let before = &text[..text.find(name).unwrap()];
+            before[before.rfind('\x1b').unwrap()..].to_string()
         };
-        print_tree(child, conn, &next_prefix, stats, opts, w);
+        assert_eq!(style_in(legend, "directory"), style_of(tree, "src"));
+        assert_eq!(style_in(legend, ".rs"), style_of(tree, "main.rs"));
+        assert_eq!(style_in(legend, ".md"), style_of(tree, "notes.md"));
+        assert_ne!(style_in(legend, ".rs"), style_in(legend, ".md"));
     }
-}
 
-fn render_node(
-    node: &TreeNode,
-    connector: &str,
-    prefix: &str,
-    opts: &PrintOptions,
-    w: &mut dyn FnMut(&str),
-) {
-    if opts.long_format {
-        let (stats, name) = entry_lines(&node.path, &node.name);
-        w(&format!("{prefix}{connector}{name}"));
-        w(&format!("{prefix}    {stats}"));
-    } else {
-        let name = entry_lines(&node.path, &node.name).1;
-        w(&format!("{prefix}{connector}{name}"));
+    #[test]
+    fn fold_identical_references_the_first_copy() {
+        let dir = fixture(&[
+            ("one/a.txt", ""),
+            ("one/lib/x.rs", ""),
+            ("two/a.txt", ""),
+            ("two/lib/x.rs", ""),
+            ("three/a.txt", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--fold-identical"]);
+        let folded = format!("two <same as {}>", dir.path().join("one").display());
+        assert_eq!(
+            listed(&out),
+            [
+                "one",
+                "a.txt",
+                "lib",
+                "x.rs",
+                "three",
+                "a.txt",
+                folded.as_str()
+            ]
+        );
     }
-}
 
-fn accumulate(stats: &mut Stats, node: &TreeNode) {
-    if node.is_dir {
-        stats.dirs += 1;
-    } else {
-        stats.files += 1;
-        stats.size += node.size;
+    #[test]
+    fn min_lines_keeps_long_text_files_only() {
+        let dir = fixture(&[
+            ("long.rs", "a\nb\nc"),
+            ("short.rs", "a\n"),
+            ("blob.bin", "a\nb\nc\n\0"),
+            ("small/one.txt", "a"),
+            ("src/lib.rs", "a\nb\nc\nd\n"),
+        ]);
+        let out = run_ok(dir.path(), &["--min-lines", "3"]);
+        assert_eq!(listed(&out), ["long.rs", "src", "lib.rs"]);
     }
-}
 
-fn print_ascii_tree(root: &TreeNode, opts: &PrintOptions, root_path: &Path) {
-    let mut stats = Stats {
-        dirs: 0,
-        files: 0,
-        size: 0,
-    };
+    #[cfg(unix)]
+    #[test]
+    fn min_lines_never_opens_special_files() {
+        let dir = fixture(&[("a.txt", "one\n")]);
+        make_fifo(&dir.path().join("pipe"));
+        std::os::unix::fs::symlink("a.txt", dir.path().join("link")).unwrap();
+
+        // opening the FIFO would block with no writer, so build on a thread we can abandon
+        let root = dir.path().to_path_buf();
+        let (done, result) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let node = tree(&root, &["--min-lines", "1"]);
+            let names: Vec<String> = node
+                .children
+                .into_iter()
+                .flatten()
+                .map(|n| n.name)
+                .collect();
+            done.send(names).unwrap();
+        });
+        let names = result
+            .recv_timeout(Duration::from_secs(10))
+            .expect("--min-lines blocked on a FIFO");
+        assert_eq!(names, ["a.txt"]);
+    }
 
-    println!("{}", root_path.display());
+    #[test]
+    fn glob_root_splits_the_literal_prefix_off() {
+        assert_eq!(
+            split_glob_root(Path::new("proj/src/*.rs")),
+            Some((PathBuf::from("proj/src"), "*.rs".to_string()))
+        );
+        assert_eq!(
+            split_glob_root(Path::new("**/*.rs")),
+            Some((PathBuf::from("."), "**/*.rs".to_string()))
+        );
+        assert_eq!(split_glob_root(Path::new("proj/src")), None);
 
-    let mut push_line = |line: &str| println!("{line}");
+        let dir = fixture(&[
+            ("src/a.rs", ""),
+            ("src/b.txt", ""),
+            ("src/bin/c.rs", ""),
+            ("docs/d.rs", ""),
+        ]);
+        let shallow = dir.path().join("src/*.rs");
+        let out = run_ok(&shallow, &["--glob-root"]);
+        assert!(out.starts_with(&format!("{}\n", dir.path().join("src").display())));
+        assert_eq!(listed(&out), ["a.rs"]);
+        let deep = dir.path().join("**/*.rs");
+        let out = run_ok(&deep, &["--glob-root"]);
+        assert_eq!(listed(&out), ["docs", "d.rs", "src", "a.rs", "bin", "c.rs"]);
+    }
 
-    if let Some(children) = root.children.as_ref() {
-        let last = children.len().saturating_sub(1);
-        for (idx, child) in children.iter().enumerate() {
-            let is_last = idx == last;
-            let connector = if is_last { "└── " } else { "├── " };
-            let prefix = if is_last { "    " } else { "│   " };
+    #[test]
+    fn compare_reports_drift_from_a_snapshot() {
+        let dir = fixture(&[
+            ("src/lib.rs", "a"),
+            ("src/main.rs", "b"),
+            ("README.md", "c"),
+        ]);
+        let scratch = tempfile::tempdir().unwrap();
+        let whole = scratch.path().join("whole.json");
+        let children = scratch.path().join("children.json");
+        let whole_arg = whole.to_str().unwrap();
+        run_ok(dir.path(), &["-q", "--json", whole_arg]);
+        let children_arg = children.to_str().unwrap();
+        run_ok(
+            dir.path(),
+            &["-q", "--json-children-only", "--json", children_arg],
+        );
 
-            print_tree(child, connector, prefix, &mut stats, opts, &mut push_line);
+        for snapshot in [whole_arg, children_arg] {
+            let (out, result) = try_run(dir.path(), &["--compare", snapshot]);
+            assert_eq!(out, "");
+            assert!(result.is_ok());
         }
+
+        fs::write(dir.path().join("src/lib.rs"), "longer").unwrap();
+        fs::remove_file(dir.path().join("README.md")).unwrap();
+        fs::write(dir.path().join("NEW"), "").unwrap();
+        let (out, result) = try_run(dir.path(), &["--compare", whole_arg]);
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            [
+                "+ NEW",
+                "- README.md",
+                "~ src/lib.rs (size 1.0 B -> 6.0 B, modified)"
+            ]
+        );
+        assert!(result.is_err());
     }
 
-    println!(
-        "\n{} directories, {} files, {} bytes total",
-        stats.dirs,
-        stats.files,
-        format_size(stats.size)
-    );
-}
+    #[test]
+    fn compare_reads_a_children_only_snapshot_of_one_directory() {
+        let dir = fixture(&[("src/lib.rs", ""), ("src/main.rs", "")]);
+        let scratch = tempfile::tempdir().unwrap();
+        let snapshot = scratch.path().join("children.json");
+        let snapshot = snapshot.to_str().unwrap();
+        run_ok(
+            dir.path(),
+            &["-q", "--json-children-only", "--json", snapshot],
+        );
+        let (out, result) = try_run(dir.path(), &["--compare", snapshot]);
+        assert_eq!(out, "");
+        assert!(result.is_ok());
+    }
 
-fn entry_lines(path: &Path, name: &str) -> (String, String) {
-    let is_hidden = name.starts_with('.') && name != "." && name != "..";
-    let styled_name = if path.is_dir() {
-        if is_hidden {
-            name.blue().bold().dimmed().underline()
-        } else {
-            name.blue().bold()
-        }
-    } else if is_hidden {
-        name.dimmed().underline()
-    } else {
-        match path
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(|e| e.to_lowercase())
-        {
-            Some(ext) if ext == "rs" => name.red().bold(),
-            Some(ext) if ext == "py" => name.yellow().bold(),
-            Some(ext) if ["c", "cpp", "h", "hpp"].contains(&ext.as_str()) => name.cyan().bold(),
-            Some(ext) if ext == "cs" => name.magenta().bold(),
-            Some(ext) if ext == "ml" || ext == "mli" => name.bright_green().bold(),
-            Some(ext) if ext == "md" => name.white().italic(),
-            Some(ext) if ext == "txt" => name.dimmed(),
-            Some(ext) if ext == "json" => name.bright_yellow().bold(),
-            _ => name.normal(),
-        }
-    };
+    #[test]
+    fn compare_uses_the_shape_recorded_by_annotate() {
+        // a root named src holding only src/: the bare shapes look alike
+        let dir = fixture(&[("src/src/lib.rs", "")]);
+        let root = dir.path().join("src");
+        let scratch = tempfile::tempdir().unwrap();
+        for extra in [&[][..], &["--json-children-only"][..]] {
+            let snapshot = scratch.path().join("snapshot.json");
+            let snapshot = snapshot.to_str().unwrap();
+            let flags = [&["-q", "--annotate", "--json", snapshot][..], extra].concat();
+            run_ok(&root, &flags);
+            let json: serde_json::Value =
+                serde_json::from_slice(&fs::read(snapshot).unwrap()).unwrap();
+            assert_eq!(json["meta"]["json_children_only"], !extra.is_empty());
+            assert!(json["tree"][0].get("scan_root").is_none());
 
-    let (size, modified, created) = match fs::metadata(path) {
-        Ok(ref md) => {
-            let size = format_size(md.len());
-            let modified = md
-                .modified()
-                .ok()
-                .map(format_time)
-                .unwrap_or_else(|| "-".into());
-            let created = md
-                .created()
-                .ok()
-                .map(format_time)
-                .unwrap_or_else(|| "-".into());
-            (size, modified, created)
+            let (out, result) = try_run(&root, &["--compare", snapshot]);
+            assert_eq!(out, "", "{extra:?}");
+            assert!(result.is_ok());
         }
-        Err(_) => ("-".into(), "-".into(), "-".into()),
-    };
+    }
 
-    let stats_line = format!(
-        "{:<10} {:<12} {:<10} {:<20} {:<10} {:<20}",
-        "Size:", size, "Modified:", modified, "Created:", created
-    );
+    #[test]
+    fn max_lines_stops_the_tree_with_a_note() {
+        let dir = fixture(&[
+            ("a/1", ""),
+            ("a/2", ""),
+            ("a/3", ""),
+            ("b/4", ""),
+            ("c", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--max-lines", "4"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[1..4], ["│   ├── a", "│   │   ├── 1", "│   │   ├── 2"]);
+        assert_eq!(lines[4], "... truncated at 4 lines (4 more not shown)");
+        assert_eq!(lines[5], "");
+        let whole = run_ok(dir.path(), &["--max-lines", "100"]);
+        assert!(!whole.contains("truncated"), "{whole}");
+    }
 
-    (stats_line, styled_name.to_string())
-}
+    #[cfg(unix)]
+    #[test]
+    fn no_metadata_lists_the_same_names_without_stats() {
+        let dir = fixture(&[
+            ("src/lib.rs", "12345"),
+            ("README.md", "abc"),
+            ("empty/", ""),
+        ]);
+        std::os::unix::fs::symlink("README.md", dir.path().join("link")).unwrap();
+        let names = |out: &str| -> Vec<String> {
+            listed(out)
+                .iter()
+                .map(|row| {
+                    row.split("@ ->")
+                        .next()
+                        .unwrap()
+                        .trim_end_matches('@')
+                        .to_string()
+                })
+                .collect()
+        };
+        let fast = run_ok(dir.path(), &["--no-metadata"]);
+        assert_eq!(names(&fast), names(&run_ok(dir.path(), &[])));
+        // nothing is read for a row, not even the link target
+        assert!(listed(&fast).contains(&"link@"), "{fast}");
 
-fn format_size(bytes: u64) -> String {
-    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
-    let mut size = bytes as f64;
-    let mut i = 0;
-    while size >= 1024.0 && i < UNITS.len() - 1 {
-        size /= 1024.0;
-        i += 1;
+        let node = tree(dir.path(), &["--no-metadata"]);
+        let (_, opts) = options(dir.path(), &["--no-metadata"]);
+        let readme = node
+            .children
+            .iter()
+            .flatten()
+            .find(|n| n.name == "README.md")
+            .unwrap();
+        assert_eq!((readme.size, readme.mtime), (0, SystemTime::UNIX_EPOCH));
+        let (stats, _) = entry_lines(readme, &readme.name, NameStyle::Kind, &opts);
+        assert!(!stats.contains("3.0 B"), "{stats}");
+
+        for flag in [
+            ["--long"],
+            ["--perms"],
+            ["--size-inline"],
+            ["--dedup-links"],
+        ] {
+            let mut flags = vec!["--no-metadata"];
+            flags.extend(flag);
+            assert!(parse(dir.path(), &flags).is_err(), "{flag:?}");
+        }
+        assert!(parse(dir.path(), &["--no-metadata", "--sort", "fs"]).is_err());
     }
-    format!("{:.1} {:<2}", size, UNITS[i])
-}
 
-fn format_time(system_time: SystemTime) -> String {
-    let datetime: DateTime<Local> = system_time.into();
-    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
-}
+    #[cfg(unix)]
+    #[test]
+    fn disk_usage_reads_the_filesystem_of_the_root() {
+        let dir = fixture(&[("a.txt", "abc")]);
+        let disk = DiskUsage::of(dir.path()).unwrap();
+        assert!(disk.total > 0);
+        assert!(disk.used <= disk.total && disk.free <= disk.total);
+        assert!(DiskUsage::of(&dir.path().join("missing")).is_err());
 
-fn write_tree_json<P>(nodes: &[TreeNode], dest: Option<P>) -> Result<(), ParseError>
-where
-    P: AsRef<Path>,
-{
-    let raw_path = dest
-        .map(|p| p.as_ref().to_path_buf())
-        .unwrap_or_else(|| PathBuf::from("tree.json"));
+        let out = run_ok(dir.path(), &["--disk"]);
+        let line = out.lines().last().unwrap();
+        assert!(
+            line.starts_with("disk: ") && line.contains(" free); the tree is "),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn web_writes_a_viewer_beside_the_json_export() {
+        let dir = fixture(&[("src/lib.rs", "x"), ("README.md", "")]);
+        let site = tempfile::tempdir().unwrap();
+        let dest = site.path().join("site");
+        run_ok(dir.path(), &["-q", "--web", dest.to_str().unwrap()]);
+        let html = fs::read_to_string(dest.join("index.html")).unwrap();
+        assert!(html.contains("tree.json"), "{html}");
+        let web: serde_json::Value =
+            serde_json::from_slice(&fs::read(dest.join("tree.json")).unwrap()).unwrap();
+        assert_eq!(web, json_export(dir.path(), &[]));
+    }
 
-    let path = ensure_json_path(raw_path);
+    #[cfg(unix)]
+    #[test]
+    fn strict_utf8_rejects_a_non_utf8_name() {
+        use std::os::unix::ffi::OsStrExt;
 
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).map_err(|e| {
-            ParseError::Tree(TreeParseError {
-                details: TreeParseType::Io(format!("creating {parent:?}: {e}")),
-            })
-        })?;
+        let dir = fixture(&[("sub/fine.txt", "")]);
+        fs::write(
+            dir.path().join("sub").join(OsStr::from_bytes(b"bad\xff")),
+            "",
+        )
+        .unwrap();
+        assert_eq!(listed(&run_ok(dir.path(), &[])).len(), 3);
+        let (out, result) = try_run(dir.path(), &["--strict-utf8"]);
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("sub/bad") && err.contains("not a valid UTF-8 name"),
+            "{err}"
+        );
+        assert!(out.is_empty(), "{out}");
     }
 
-    let json_bytes = serde_json::to_vec_pretty(nodes).map_err(|e| {
-        ParseError::Tree(TreeParseError {
-            details: TreeParseType::InvalidInput(format!("serialising JSON: {e}")),
-        })
-    })?;
+    #[test]
+    fn highlight_marks_matches_and_keeps_everything() {
+        let dir = fixture(&[("main.rs", ""), ("notes.txt", ""), ("test_main.py", "")]);
+        let plain = run_colored(dir.path(), &[]);
+        let out = run_colored(dir.path(), &["--highlight", "main"]);
+        assert_eq!(style_of(&out, "main.rs"), "\x1b[1;7m");
+        assert_eq!(style_of(&out, "test_main.py"), "\x1b[1;7m");
+        assert_eq!(style_of(&out, "notes.txt"), style_of(&plain, "notes.txt"));
+        assert_eq!(out.lines().count(), plain.lines().count());
+    }
 
-    fs::write(&path, json_bytes).map_err(|e| {
-        ParseError::Tree(TreeParseError {
-            details: TreeParseType::Io(format!("writing {path:?}: {e}")),
-        })
-    })
-}
+    #[test]
+    fn sexp_export_nests_by_depth() {
+        let dir = fixture(&[("a/b/deep.txt", "xyz"), ("top \"q\".txt", "")]);
+        let dest = tempfile::tempdir().unwrap();
+        let file = dest.path().join("tree.sexp");
+        run_ok(dir.path(), &["-q", "--sexp", file.to_str().unwrap()]);
+        let sexp = fs::read_to_string(&file).unwrap();
 
-fn ensure_json_path<P: AsRef<Path>>(p: P) -> PathBuf {
-    let path = p.as_ref();
+        let mut open = 0usize;
+        let mut escaped = false;
+        let mut quoted = false;
+        for line in sexp.lines() {
+            let indent = line.len() - line.trim_start().len();
+            assert_eq!(indent / 2, open, "{line}");
+            for c in line.chars() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' if quoted => escaped = true,
+                    '"' => quoted = !quoted,
+                    '(' if !quoted => open += 1,
+                    ')' if !quoted => open -= 1,
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(open, 0);
+        assert!(sexp.contains("\n  (dir \"a\"\n    (dir \"b\"\n      (file \"deep.txt\" 3)))"));
+        assert!(sexp.contains("(file \"top \\\"q\\\".txt\" 0)"));
+    }
 
-    if path
-        .extension()
-        .and_then(|e| e.to_str())
-        .map(|e| e.eq_ignore_ascii_case("json"))
-        == Some(true)
-    {
-        return path.to_path_buf();
+    #[test]
+    fn exclude_empty_files_drops_zero_byte_files_only() {
+        let dir = fixture(&[("sub/zero.txt", ""), ("sub/one.txt", "x"), ("top.txt", "")]);
+        for flag in ["--exclude-empty-files", "--no-empty"] {
+            let out = run_ok(dir.path(), &[flag]);
+            assert_eq!(listed(&out), ["sub", "one.txt"], "{flag}");
+        }
+        let out = run_ok(dir.path(), &[]);
+        assert!(listed(&out).contains(&"zero.txt"));
     }
 
-    let mut dir = path.to_path_buf();
-    dir.push("tree.json");
-    dir
-}
+    #[test]
+    fn per_top_level_totals_each_directory() {
+        let dir = fixture(&[
+            ("big/a.bin", &"x".repeat(2048)),
+            ("big/nested/b.bin", &"x".repeat(1024)),
+            ("small/c.txt", "abc"),
+            ("loose.txt", "ignored"),
+        ]);
+        let out = run_ok(dir.path(), &["-q", "--per-top-level"]);
+        let rows: Vec<Vec<&str>> = out
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                vec!["size", "files", "directory"],
+                vec!["3.0", "KB", "2", "big/"],
+                vec!["3.0", "B", "1", "small/"],
+            ]
+        );
+    }
 
-fn emit_json(tree: &TreeNode, dest_raw: &str) -> Result<(), ParseError> {
-    let dest: Option<&Path> = if dest_raw.trim().is_empty() {
-        None
-    } else {
-        Some(Path::new(dest_raw))
-    };
+    #[test]
+    fn tilde_shortens_paths_under_home() {
+        let dir = fixture(&[("proj/a.txt", "")]);
+        assert_eq!(home_dir(Some(OsString::new())), None);
+        assert_eq!(home_dir(None), None);
+        let (_, parsed) = options(dir.path(), &["--tilde"]);
+        assert_eq!(parsed.tilde_home, home_dir(std::env::var_os("HOME")));
 
-    write_tree_json(std::slice::from_ref(tree), dest)?;
+        let (path, mut opts) = options(&dir.path().join("proj"), &[]);
+        opts.tilde_home = home_dir(Some(dir.path().into()));
+        assert_eq!(opts.tilde_home.as_deref(), Some(dir.path()));
+        let out = {
+            let _output = lock_output(false);
+            let mut out = Vec::new();
+            run_with_options(&path, &opts, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(out.lines().next(), Some("~/proj"));
+        assert_eq!(shown_path(dir.path(), &opts), "~");
+        assert_eq!(shown_path(Path::new("/elsewhere"), &opts), "/elsewhere");
+    }
 
-    println!(
-        "Wrote directory tree to {}",
-        dest.map(|p| p.display().to_string())
-            .unwrap_or_else(|| "file.json".into())
-    );
+    #[test]
+    fn sort_children_puts_busiest_directories_first() {
+        let dir = fixture(&[
+            ("a/1", ""),
+            ("b/1", ""),
+            ("b/2", ""),
+            ("b/3", ""),
+            ("c/1", ""),
+            ("c/2", ""),
+            ("file.txt", ""),
+        ]);
+        let root = tree(dir.path(), &["--sort", "children"]);
+        let names: Vec<&str> = root
+            .children
+            .iter()
+            .flatten()
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(names, ["b", "c", "a", "file.txt"]);
 
-    Ok(())
-}
-pub fn run(args: Args) -> io::Result<()> {
-    let path = &args.path.clone();
-    let opts = create_print_options_from_args(args)?;
-    let tree = build_directory_tree(path, &opts)?;
+        let args = parse(dir.path(), &["--sort", "size"]).unwrap();
+        let Err(ParseError::Args(err)) = create_scan_options_from_args(args) else {
+            panic!("--sort size was not an argument error");
+        };
+        assert_eq!(
+            err.details.to_string(),
+            "invalid sort flag \"size\" (expected \"fs\", \"ts\" or \"children\")"
+        );
+    }
 
-    if let Some(ref raw_dest) = opts.write_json {
-        emit_json(&tree, raw_dest)?;
-        return Ok(());
+    #[test]
+    fn index_prints_every_relative_directory() {
+        let dir = fixture(&[
+            ("src/bin/main.rs", ""),
+            ("src/lib.rs", ""),
+            ("docs/guide.md", ""),
+            ("top.txt", ""),
+        ]);
+        let out = run_ok(dir.path(), &["--index"]);
+        assert_eq!(out.lines().collect::<Vec<_>>(), ["docs", "src", "src/bin"]);
     }
 
-    print_ascii_tree(&tree, &opts, path);
-    Ok(())
+    #[cfg(unix)]
+    #[test]
+    fn broken_links_reports_dangling_symlinks_with_targets() {
+        let dir = fixture(&[("sub/real.txt", "x")]);
+        std::os::unix::fs::symlink("real.txt", dir.path().join("sub/ok")).unwrap();
+        std::os::unix::fs::symlink("missing.txt", dir.path().join("sub/gone")).unwrap();
+        let out = run_ok(dir.path(), &["--broken-links"]);
+        let gone = dir.path().join("sub/gone");
+        assert_eq!(out, format!("{} -> missing.txt\n", gone.display()));
+    }
 }